@@ -0,0 +1,364 @@
+//! Maintenance subcommands, run without starting the sync loop.
+
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand};
+use luoxu_rs::{get_session, LuoxuBotContext, LuoxuConfig, LuoxuMessage};
+use matrix_sdk::ruma::{OwnedRoomId, RoomAliasId};
+
+/// Number of messages written to an index at once.
+const BATCH_SIZE: usize = 1000;
+
+#[derive(Parser)]
+#[command(version, about = "Index and search Matrix rooms")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<AdminCommand>,
+}
+
+#[derive(Subcommand)]
+pub enum AdminCommand {
+    /// Manage the search indices.
+    #[command(subcommand)]
+    Index(IndexCommand),
+    /// Manage the indexed rooms.
+    #[command(subcommand)]
+    Rooms(RoomsCommand),
+    /// Validate luoxu-rs.toml, the connectivity and the rooms to index.
+    Check,
+}
+
+#[derive(Subcommand)]
+pub enum IndexCommand {
+    /// List the indices and their rooms.
+    List,
+    /// Create an index.
+    Create { index: String },
+    /// Delete an index and its messages.
+    Delete { index: String },
+    /// Index the messages of an index again, e.g. after changing its Chinese variant.
+    Reindex { index: String },
+    /// Show the settings of an index.
+    Settings { index: String },
+    /// Queue the changes the bot gave up writing to the search backend again.
+    Requeue,
+    /// Copy the messages of the per-room indices to the shared index.
+    Migrate {
+        /// Delete the per-room indices once copied.
+        #[arg(long)]
+        delete: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum RoomsCommand {
+    /// List the indexed rooms.
+    List,
+    /// Index a room, backfilled when the bot starts.
+    Add {
+        /// The room ID or alias.
+        room: String,
+        index: String,
+    },
+    /// Stop indexing a room, keeping its indexed messages.
+    Remove {
+        /// The room ID or alias.
+        room: String,
+    },
+    /// Move a room and its messages to another index.
+    RenameIndex {
+        /// The room ID or alias.
+        room: String,
+        index: String,
+    },
+}
+
+pub async fn run(command: AdminCommand, config: LuoxuConfig) -> anyhow::Result<()> {
+    if let AdminCommand::Check = command {
+        return check(&config).await;
+    }
+    let context = config.get_context()?;
+    match command {
+        AdminCommand::Index(command) => index(command, &config, &context).await,
+        AdminCommand::Rooms(command) => rooms(command, &config, &context).await,
+        AdminCommand::Check => unreachable!(),
+    }
+}
+
+async fn index(
+    command: IndexCommand,
+    config: &LuoxuConfig,
+    ctx: &LuoxuBotContext,
+) -> anyhow::Result<()> {
+    match command {
+        IndexCommand::List => {
+            let rooms = ctx.store.get_rooms()?;
+            let indices = ctx.search.list_indices().await?;
+            for index in &indices {
+                let names: Vec<_> = rooms
+                    .iter()
+                    .filter(|info| info.index_name == *index)
+                    .map(|info| info.room_name.as_deref().unwrap_or(&info.room_id))
+                    .collect();
+                println!("{}\t{}", index, names.join(", "));
+            }
+            for info in rooms
+                .iter()
+                .filter(|info| !indices.contains(&info.index_name))
+            {
+                println!("{}\t(missing, used by {})", info.index_name, info.room_id);
+            }
+        }
+        IndexCommand::Create { index } => {
+            ctx.search.create_index(&index).await?;
+            println!("Created index {}", index);
+        }
+        IndexCommand::Delete { index } => {
+            let rooms: Vec<_> = ctx
+                .store
+                .get_rooms()?
+                .into_iter()
+                .filter(|info| info.index_name == index)
+                .map(|info| info.room_id)
+                .collect();
+            if !rooms.is_empty() {
+                bail!(
+                    "Index {} is used by {}, remove or move these rooms first",
+                    index,
+                    rooms.join(", ")
+                );
+            }
+            ctx.search.delete_index(&index).await?;
+            println!("Deleted index {}", index);
+        }
+        IndexCommand::Reindex { index } => {
+            let mut messages = ctx.search.documents(&index, &[]).await?;
+            for msg in &mut messages {
+                msg.apply_edits(&ctx.store.get_edits(&msg.event_id.event_id())?);
+                msg.refresh_link();
+                ctx.normalizer.normalize_message(&index, msg);
+            }
+            ctx.search.create_index(&index).await?;
+            add_messages(ctx, &index, &messages).await?;
+            println!("Reindexed {} messages in {}", messages.len(), index);
+        }
+        IndexCommand::Settings { index } => {
+            let settings = ctx.search.settings(&index).await?;
+            println!("{}", serde_json::to_string_pretty(&settings)?);
+        }
+        IndexCommand::Requeue => {
+            let count = ctx.store.requeue_failed_outbox()?;
+            println!("Queued {} changes again", count);
+        }
+        IndexCommand::Migrate { delete } => {
+            let shared = match &config.search.shared_index {
+                Some(shared) => shared,
+                None => bail!("Migrating requires search.shared_index to be set"),
+            };
+            // The configured backend only sees the shared index, use the indices below it.
+            let search = config.get_search_backend()?;
+            let indices = search.list_indices().await?;
+            let mut groups: Vec<_> = ctx
+                .store
+                .get_rooms()?
+                .into_iter()
+                .map(|info| info.index_name)
+                .filter(|index| index != shared && indices.contains(index))
+                .collect();
+            groups.sort();
+            groups.dedup();
+            search.create_index(shared).await?;
+            for group in groups {
+                let messages = search.documents(&group, &[]).await?;
+                for chunk in messages.chunks(BATCH_SIZE) {
+                    search.add_or_update(shared, chunk).await?;
+                }
+                if delete {
+                    search.delete_index(&group).await?;
+                }
+                println!(
+                    "Copied {} messages from {} to {}",
+                    messages.len(),
+                    group,
+                    shared
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn rooms(
+    command: RoomsCommand,
+    config: &LuoxuConfig,
+    ctx: &LuoxuBotContext,
+) -> anyhow::Result<()> {
+    match command {
+        RoomsCommand::List => {
+            for info in ctx.store.get_rooms()? {
+                let status = match &info.inactive {
+                    Some(inactive) => format!("\t(inactive, {:?})", inactive.reason).to_lowercase(),
+                    None => String::new(),
+                };
+                println!(
+                    "{}\t{}\t{}{}",
+                    info.room_id,
+                    info.index_name,
+                    info.room_name.unwrap_or_default(),
+                    status
+                );
+            }
+        }
+        RoomsCommand::Add { room, index } => {
+            let room_id = resolve_room(config, &room).await?;
+            if let Some(existing) = ctx.store.get_index(room_id.clone())? {
+                bail!("{} is already indexed in {}", room_id, existing);
+            }
+            ctx.search.create_index(&index).await?;
+            ctx.store.add_entry(room_id.as_str(), &index, None)?;
+            println!("Indexing {} in {}", room_id, index);
+        }
+        RoomsCommand::Remove { room } => {
+            let room_id = resolve_room(config, &room).await?;
+            if !ctx.store.remove_entry(room_id.as_str())? {
+                bail!("{} is not indexed", room_id);
+            }
+            println!("Stopped indexing {}", room_id);
+        }
+        RoomsCommand::RenameIndex { room, index } => {
+            let room_id = resolve_room(config, &room).await?;
+            let old_index = match ctx.store.get_index(room_id.clone())? {
+                Some(old_index) => old_index,
+                None => bail!("{} is not indexed", room_id),
+            };
+            if old_index == index {
+                return Ok(());
+            }
+            let mut messages = ctx
+                .search
+                .documents(&old_index, std::slice::from_ref(&room_id))
+                .await?;
+            for msg in &mut messages {
+                ctx.normalizer.normalize_message(&index, msg);
+            }
+            ctx.search.create_index(&index).await?;
+            add_messages(ctx, &index, &messages).await?;
+            ctx.store
+                .update_entry(room_id.as_str(), Some(&index), None)?;
+            let event_ids: Vec<_> = messages.into_iter().map(|msg| msg.event_id).collect();
+            // Both groups of a shared index point to the same messages, which are kept.
+            if config.search.shared_index.is_none() {
+                for chunk in event_ids.chunks(BATCH_SIZE) {
+                    ctx.search.delete(&old_index, chunk).await?;
+                }
+            }
+            println!(
+                "Moved {} and {} messages from {} to {}",
+                room_id,
+                event_ids.len(),
+                old_index,
+                index
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn add_messages(
+    ctx: &LuoxuBotContext,
+    index: &str,
+    messages: &[LuoxuMessage],
+) -> anyhow::Result<()> {
+    for chunk in messages.chunks(BATCH_SIZE) {
+        ctx.search.add_or_update(index, chunk).await?;
+    }
+    Ok(())
+}
+
+/// Get the ID of a room from its ID or alias.
+async fn resolve_room(config: &LuoxuConfig, room: &str) -> anyhow::Result<OwnedRoomId> {
+    if room.starts_with('#') {
+        let client = matrix_sdk::Client::builder()
+            .homeserver_url(&config.matrix.homeserver_url)
+            .build()
+            .await?;
+        let room_alias = <&RoomAliasId>::try_from(room)?;
+        Ok(client.resolve_room_alias(room_alias).await?.room_id)
+    } else {
+        Ok(OwnedRoomId::try_from(room)?)
+    }
+}
+
+async fn check(config: &LuoxuConfig) -> anyhow::Result<()> {
+    let mut failures = 0;
+    let mut report = |name: &str, result: anyhow::Result<String>| match result {
+        Ok(detail) => println!("[ OK ] {}: {}", name, detail),
+        Err(e) => {
+            failures += 1;
+            println!("[FAIL] {}: {:#}", name, e);
+        }
+    };
+    report("Configuration", Ok("luoxu-rs.toml is valid".to_string()));
+
+    let context = config.get_context();
+    let search = match &context {
+        Ok(context) => context.search.list_indices().await.map(|indices| {
+            format!(
+                "{:?} backend, {} indices",
+                config.search.backend,
+                indices.len()
+            )
+        }),
+        Err(e) => Err(anyhow::anyhow!("{:#}", e)),
+    };
+    report("State and search backend", search);
+
+    let versions = async {
+        let url = format!(
+            "{}/_matrix/client/versions",
+            config.matrix.homeserver_url.trim_end_matches('/')
+        );
+        matrix_sdk::reqwest::get(&url).await?.error_for_status()?;
+        Ok(config.matrix.homeserver_url.clone())
+    };
+    report("Homeserver", versions.await);
+
+    let credentials = match (get_session(), &config.matrix.password) {
+        (Ok(session), _) => Ok(format!("saved session of {}", session.user_id)),
+        (Err(_), Some(_)) => Ok("password".to_string()),
+        (Err(_), None) => Err(anyhow::anyhow!(
+            "credentials.json not found and no password configured"
+        )),
+    };
+    report("Credentials", credentials);
+
+    if let Some(ocr) = &config.ocr {
+        let version = tokio::process::Command::new(&ocr.command)
+            .arg("--version")
+            .output()
+            .await
+            .with_context(|| format!("Running {} failed", ocr.command))
+            .and_then(|output| match output.status.success() {
+                true => Ok(ocr.command.clone()),
+                false => Err(anyhow::anyhow!(
+                    "{} exited with {}",
+                    ocr.command,
+                    output.status
+                )),
+            });
+        report("OCR", version);
+    }
+
+    let mut indices: Vec<_> = config.matrix.indices.iter().collect();
+    indices.sort();
+    for (index, room) in indices {
+        let room_id = resolve_room(config, room)
+            .await
+            .map(|room_id| format!("{} -> {}", room, room_id));
+        report(&format!("Index {}", index), room_id);
+    }
+
+    if failures > 0 {
+        bail!("{} checks failed", failures);
+    }
+    Ok(())
+}
@@ -0,0 +1,268 @@
+use luoxu_rs::{BackfillState, KeyEventId, LuoxuBotContext, SyncGap};
+use matrix_sdk::deserialized_responses::{SyncResponse, TimelineEvent};
+use matrix_sdk::room::{MessagesOptions, Room};
+use matrix_sdk::ruma::api::client::filter::RoomEventFilter;
+use matrix_sdk::ruma::events::room::redaction::SyncRoomRedactionEvent;
+use matrix_sdk::ruma::events::{
+    AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+};
+use matrix_sdk::ruma::{RoomId, UInt};
+use std::sync::Arc;
+
+use crate::callbacks::{
+    convert_timeline_event, queue_ocr, queue_undecrypted, redact_edits, save_converted,
+    save_messages, ConvertedMessage,
+};
+
+/// Number of events requested per `/messages` call.
+const BACKFILL_PAGE_SIZE: u32 = 100;
+
+/// Backfill the history of every room in the index map.
+pub async fn backfill_rooms(
+    client: matrix_sdk::Client,
+    ctx: Arc<LuoxuBotContext>,
+) -> anyhow::Result<()> {
+    for info in ctx.store.get_rooms()? {
+        let room_id = <&RoomId>::try_from(info.room_id.as_str())?;
+        match client.get_room(room_id) {
+            Some(room) => {
+                if let Err(e) = backfill_room(&client, &ctx, &room, &info.index_name).await {
+                    tracing::warn!("Backfilling {} failed: {}", room_id, e);
+                }
+            }
+            None => tracing::warn!("Not backfilling {} as the bot is not in the room", room_id),
+        }
+    }
+    Ok(())
+}
+
+/// Paginate backwards through a room's history, resuming from the saved token.
+pub async fn backfill_room(
+    client: &matrix_sdk::Client,
+    ctx: &LuoxuBotContext,
+    room: &Room,
+    index: &str,
+) -> anyhow::Result<()> {
+    let room_id = room.room_id().as_str();
+    let mut token = match ctx.store.get_backfill_state(room_id)? {
+        Some(BackfillState::Completed) => return Ok(()),
+        Some(BackfillState::Paginating(token)) => Some(token),
+        None => None,
+    };
+    tracing::info!("Backfilling {} into index {}", room_id, index);
+    loop {
+        let mut options = MessagesOptions::backward().from(token.as_deref());
+        options.limit = UInt::from(BACKFILL_PAGE_SIZE);
+        let messages = room.messages(options).await?;
+        save_events(client, ctx, room, index, messages.chunk).await?;
+
+        let state = match messages.end {
+            Some(end) if messages.start != end => BackfillState::Paginating(end),
+            _ => BackfillState::Completed,
+        };
+        ctx.store.set_backfill_state(room_id, &state)?;
+        match state {
+            BackfillState::Paginating(end) => token = Some(end),
+            BackfillState::Completed => break,
+        }
+    }
+    tracing::info!("Backfilling {} completed", room_id);
+    Ok(())
+}
+
+/// Index a page of events returned by `/messages`.
+async fn save_events(
+    client: &matrix_sdk::Client,
+    ctx: &LuoxuBotContext,
+    room: &Room,
+    index: &str,
+    events: Vec<TimelineEvent>,
+) -> anyhow::Result<()> {
+    let mut msgs = Vec::new();
+    let mut images = Vec::new();
+    for event in events {
+        // The SDK leaves the events it failed to decrypt encrypted.
+        if let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomEncrypted(
+            SyncMessageLikeEvent::Original(ev),
+        ))) = event.event.deserialize_as::<AnySyncTimelineEvent>()
+        {
+            queue_undecrypted(ctx, room, &ev.event_id, event.event.json().get())?;
+            continue;
+        }
+        match convert_timeline_event(event.event, room, client).await? {
+            Some(ConvertedMessage::Message(msg, image)) => {
+                if let Some(source) = image {
+                    images.push((msg.event_id.clone(), source));
+                }
+                msgs.push(msg);
+            }
+            Some(edit) => save_converted(ctx, index, edit).await?,
+            None => {}
+        }
+    }
+    if !msgs.is_empty() {
+        save_messages(ctx, index, msgs)?;
+    }
+    for (event_id, source) in images {
+        queue_ocr(ctx, index, &event_id, source)?;
+    }
+    Ok(())
+}
+
+/// Save the token of a sync response, and backfill the events its limited
+/// timelines skipped in indexed rooms.
+pub fn handle_sync_response(
+    client: &matrix_sdk::Client,
+    ctx: &Arc<LuoxuBotContext>,
+    response: &SyncResponse,
+) -> anyhow::Result<()> {
+    let mut gaps = Vec::new();
+    if let Some(since) = ctx.store.get_sync_token()? {
+        for (room_id, room) in &response.rooms.join {
+            let from = match &room.timeline.prev_batch {
+                Some(from) if room.timeline.limited => from,
+                _ => continue,
+            };
+            if ctx.store.get_index(room_id.clone())?.is_some() {
+                let gap = SyncGap {
+                    from: from.clone(),
+                    to: since.clone(),
+                };
+                gaps.push((room_id.to_string(), gap));
+            }
+        }
+    }
+    // Record the gaps with the token, so they are still backfilled after a restart.
+    ctx.store.set_sync_token(&response.next_batch, &gaps)?;
+    for (room_id, gap) in gaps {
+        tracing::info!("Timeline of {} was limited, backfilling the gap", room_id);
+        let client = client.clone();
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = fill_gap(&client, &ctx, &room_id, gap).await {
+                tracing::warn!("Backfilling the gap in {} failed: {}", room_id, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Backfill the gaps left by limited sync timelines before the last shutdown.
+pub async fn fill_gaps(
+    client: matrix_sdk::Client,
+    ctx: Arc<LuoxuBotContext>,
+) -> anyhow::Result<()> {
+    for (room_id, gap) in ctx.store.get_gaps()? {
+        if let Err(e) = fill_gap(&client, &ctx, &room_id, gap).await {
+            tracing::warn!("Backfilling the gap in {} failed: {}", room_id, e);
+        }
+    }
+    Ok(())
+}
+
+/// Paginate backwards through a gap until reaching the previous sync token.
+pub async fn fill_gap(
+    client: &matrix_sdk::Client,
+    ctx: &LuoxuBotContext,
+    room_id: &str,
+    mut gap: SyncGap,
+) -> anyhow::Result<()> {
+    let room = client.get_room(<&RoomId>::try_from(room_id)?);
+    let index = ctx.store.get_index(<&RoomId>::try_from(room_id)?.into())?;
+    let (room, index) = match (room, index) {
+        (Some(room), Some(index)) => (room, index),
+        // The room is not indexed anymore.
+        _ => return ctx.store.remove_gap(room_id, &gap),
+    };
+    loop {
+        let mut options = MessagesOptions::backward().from(Some(gap.from.as_str()));
+        options.to = Some(&gap.to);
+        options.limit = UInt::from(BACKFILL_PAGE_SIZE);
+        let messages = room.messages(options).await?;
+        let empty = messages.chunk.is_empty();
+        save_events(client, ctx, &room, &index, messages.chunk).await?;
+
+        match messages.end {
+            Some(end) if !empty && messages.start != end => {
+                gap.from = end;
+                ctx.store.update_gap(room_id, &gap)?;
+            }
+            _ => break,
+        }
+    }
+    ctx.store.remove_gap(room_id, &gap)?;
+    tracing::info!("Backfilling the gap in {} completed", room_id);
+    Ok(())
+}
+
+/// Remove messages redacted while the bot was offline from every index.
+pub async fn reconcile_redactions(
+    client: matrix_sdk::Client,
+    ctx: Arc<LuoxuBotContext>,
+) -> anyhow::Result<()> {
+    for info in ctx.store.get_rooms()? {
+        let room_id = <&RoomId>::try_from(info.room_id.as_str())?;
+        if let Some(room) = client.get_room(room_id) {
+            if let Err(e) = reconcile_room_redactions(&client, &ctx, &room, &info.index_name).await
+            {
+                tracing::warn!("Reconciling redactions of {} failed: {}", room_id, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Paginate backwards through the redactions of a room until reaching the
+/// point where the last reconciliation started.
+pub async fn reconcile_room_redactions(
+    client: &matrix_sdk::Client,
+    ctx: &LuoxuBotContext,
+    room: &Room,
+    index: &str,
+) -> anyhow::Result<()> {
+    let room_id = room.room_id().as_str();
+    let stop = ctx.store.get_redaction_token(room_id)?;
+    let types = ["m.room.redaction".to_string()];
+    let mut token: Option<String> = None;
+    let mut checkpoint: Option<String> = None;
+    loop {
+        let mut options = MessagesOptions::backward().from(token.as_deref());
+        options.to = stop.as_deref();
+        options.limit = UInt::from(BACKFILL_PAGE_SIZE);
+        options.filter = RoomEventFilter::empty();
+        options.filter.types = Some(&types);
+        let messages = room.messages(options).await?;
+        if checkpoint.is_none() {
+            checkpoint = Some(messages.start.clone());
+        }
+
+        let redacted: Vec<_> = messages
+            .chunk
+            .iter()
+            .filter_map(
+                |event| match event.event.deserialize_as::<AnySyncTimelineEvent>() {
+                    Ok(AnySyncTimelineEvent::MessageLike(
+                        AnySyncMessageLikeEvent::RoomRedaction(SyncRoomRedactionEvent::Original(
+                            ev,
+                        )),
+                    )) => Some(KeyEventId::from(ev.redacts)),
+                    _ => None,
+                },
+            )
+            .collect();
+        if !redacted.is_empty() {
+            let event_ids: Vec<_> = redacted.iter().map(KeyEventId::event_id).collect();
+            ctx.delete_messages(index, &redacted)?;
+            redact_edits(client, ctx, room, index, &event_ids).await?;
+        }
+
+        match messages.end {
+            Some(end) if messages.start != end => token = Some(end),
+            _ => break,
+        }
+    }
+    if let Some(checkpoint) = checkpoint {
+        ctx.store.set_redaction_token(room_id, &checkpoint)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,190 @@
+//! Authentication of web users with Matrix OpenID tokens.
+//!
+//! See <https://spec.matrix.org/v1.8/client-server-api/#openid>.
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, State},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    Json,
+};
+use matrix_sdk::reqwest::Url;
+use rand::RngCore;
+use ruma::{OwnedServerName, OwnedUserId, ServerName};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::routes::{AppError, RouteResult};
+use crate::AppState;
+
+/// How long a session lasts after logging in.
+const SESSION_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Sessions of logged in users, by token.
+#[derive(Clone, Default)]
+pub struct Sessions(Arc<Mutex<HashMap<String, Session>>>);
+
+struct Session {
+    user_id: OwnedUserId,
+    expires_at: Instant,
+}
+
+impl Sessions {
+    fn create(&self, user_id: OwnedUserId) -> String {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let now = Instant::now();
+        let mut sessions = self.0.lock().unwrap();
+        sessions.retain(|_, session| session.expires_at > now);
+        sessions.insert(
+            token.clone(),
+            Session {
+                user_id,
+                expires_at: now + SESSION_LIFETIME,
+            },
+        );
+        token
+    }
+
+    fn get(&self, token: &str) -> Option<OwnedUserId> {
+        let sessions = self.0.lock().unwrap();
+        sessions
+            .get(token)
+            .filter(|session| session.expires_at > Instant::now())
+            .map(|session| session.user_id.clone())
+    }
+}
+
+/// Log in with an OpenID token, verified with the homeserver of the user.
+/// POST /login
+pub async fn login(
+    State(state): State<AppState>,
+    Json(request): Json<OpenIdToken>,
+) -> RouteResult<Json<LoginResponse>> {
+    let server_name = <&ServerName>::try_from(request.matrix_server_name.as_str())
+        .map_err(|_| AppError::new(StatusCode::BAD_REQUEST, "Invalid matrix_server_name"))?;
+    let userinfo = match verify_token(&state, server_name, &request.access_token).await {
+        Ok(userinfo) => userinfo,
+        Err(e) => {
+            tracing::info!(
+                "Verifying an OpenID token of {} failed: {:#}",
+                server_name,
+                e
+            );
+            return Err(AppError::new(
+                StatusCode::UNAUTHORIZED,
+                "Unrecognised OpenID token",
+            ));
+        }
+    };
+    // A server may only vouch for its own users.
+    if userinfo.sub.server_name() != server_name {
+        return Err(AppError::new(
+            StatusCode::UNAUTHORIZED,
+            "The user does not belong to matrix_server_name",
+        ));
+    }
+    tracing::info!("{} logged in", userinfo.sub);
+    let token = state.sessions.create(userinfo.sub);
+    Ok(Json(LoginResponse { token }))
+}
+
+/// Ask the homeserver of a user who an OpenID token belongs to.
+async fn verify_token(
+    state: &AppState,
+    server_name: &ServerName,
+    access_token: &str,
+) -> anyhow::Result<UserInfo> {
+    let server = resolve_server(state, server_name).await?;
+    let mut url = Url::parse(&format!(
+        "https://{}/_matrix/federation/v1/openid/userinfo",
+        server
+    ))?;
+    url.query_pairs_mut()
+        .append_pair("access_token", access_token);
+    let response = state.http.get(url).send().await?.error_for_status()?;
+    Ok(serde_json::from_slice(&response.bytes().await?)?)
+}
+
+/// Find the federation endpoint of a server name, with `.well-known` delegation.
+async fn resolve_server(
+    state: &AppState,
+    server_name: &ServerName,
+) -> anyhow::Result<OwnedServerName> {
+    // An explicit port skips delegation.
+    if server_name.port().is_some() {
+        return Ok(server_name.to_owned());
+    }
+    if !server_name.is_ip_literal() {
+        if let Ok(server) = well_known_server(state, server_name).await {
+            return Ok(server);
+        }
+    }
+    Ok(OwnedServerName::try_from(format!("{}:8448", server_name))?)
+}
+
+async fn well_known_server(
+    state: &AppState,
+    server_name: &ServerName,
+) -> anyhow::Result<OwnedServerName> {
+    let url = format!("https://{}/.well-known/matrix/server", server_name);
+    let response = state.http.get(url).send().await?.error_for_status()?;
+    let well_known: WellKnownServer = serde_json::from_slice(&response.bytes().await?)?;
+    // The delegated server goes into the URL as is, so it must only be a host and a port.
+    let server = OwnedServerName::try_from(well_known.server)?;
+    match server.port() {
+        Some(_) => Ok(server),
+        None => Ok(OwnedServerName::try_from(format!("{}:8448", server))?),
+    }
+}
+
+/// A logged in user, from the `Authorization: Bearer` header.
+pub struct AuthUser(pub OwnedUserId);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthUser
+where
+    S: Send + Sync,
+    Sessions: FromRef<S>,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| AppError::new(StatusCode::UNAUTHORIZED, "Missing access token"))?;
+        Sessions::from_ref(state)
+            .get(token)
+            .map(AuthUser)
+            .ok_or_else(|| AppError::new(StatusCode::UNAUTHORIZED, "Unrecognised access token"))
+    }
+}
+
+/// The token returned by `/_matrix/client/v3/user/{userId}/openid/request_token`.
+#[derive(Debug, Deserialize)]
+pub struct OpenIdToken {
+    access_token: String,
+    matrix_server_name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoginResponse {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserInfo {
+    sub: OwnedUserId,
+}
+
+#[derive(Debug, Deserialize)]
+struct WellKnownServer {
+    #[serde(rename = "m.server")]
+    server: String,
+}
@@ -0,0 +1,194 @@
+//! Conversation excerpts around a message.
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header::AUTHORIZATION, StatusCode},
+    Json,
+};
+use luoxu_rs::search::{SearchQuery, SearchSort};
+use luoxu_rs::{message_body, LuoxuMessage};
+use ruma::events::room::message::Relation;
+use ruma::events::{AnyMessageLikeEvent, AnyTimelineEvent, MessageLikeEvent};
+use ruma::serde::Raw;
+use ruma::UserId;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+
+use crate::auth::AuthUser;
+use crate::routes::{
+    check_member, check_visible, parse_event_id, AppError, MessageSearchResult, RouteResult,
+};
+use crate::AppState;
+
+/// Number of messages on each side when not specified.
+const DEFAULT_CONTEXT: usize = 5;
+/// Maximum number of messages on each side.
+const MAX_CONTEXT: usize = 50;
+
+/// Get the messages sent around a message.
+/// GET /context/:index_name/:event_id?before=N&after=N
+pub async fn context(
+    State(state): State<AppState>,
+    AuthUser(user_id): AuthUser,
+    Path((index_name, event_id)): Path<(String, String)>,
+    Query(params): Query<ContextParams>,
+) -> RouteResult<Json<MessageContext>> {
+    let search = &state.context.search;
+    let rooms = check_member(&state.context, &user_id, &index_name)?;
+    let event_id = parse_event_id(event_id)?;
+    let message = search
+        .get(&index_name, &event_id.clone().into())
+        .await?
+        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, "Message not found"))?;
+    check_visible(&rooms, &message)?;
+    // Only the messages of the same room are its context.
+    let rooms = match state.context.store.is_shared_index(&index_name)? {
+        true => vec![message.room_id.clone()],
+        false => vec![],
+    };
+    let before = params.before.unwrap_or(DEFAULT_CONTEXT).min(MAX_CONTEXT);
+    let after = params.after.unwrap_or(DEFAULT_CONTEXT).min(MAX_CONTEXT);
+
+    let mut query = SearchQuery::new("");
+    query.filter.rooms = rooms.clone();
+    query.filter.before = Some(message.timestamp);
+    query.limit = before;
+    let mut messages_before = messages(search.search(&index_name, &query).await?.hits);
+    let mut query = SearchQuery::new("");
+    query.filter.rooms = rooms;
+    query.filter.after = Some(message.timestamp);
+    query.sort = SearchSort::Oldest;
+    query.limit = after;
+    let mut messages_after = messages(search.search(&index_name, &query).await?.hits);
+
+    // The index misses messages sent while the bot wasn't running, until they're backfilled.
+    if messages_before.len() < before || messages_after.len() < after {
+        match room_context(&state, &message, before.max(after) * 2).await {
+            Ok((room_before, room_after)) => {
+                merge(&mut messages_before, room_before);
+                merge(&mut messages_after, room_after);
+            }
+            Err(e) => tracing::warn!("Fetching the context of {} failed: {}", event_id, e),
+        }
+    }
+    // Keep the closest messages, oldest first.
+    messages_before.sort_by_key(|msg| Reverse(msg.timestamp));
+    messages_before.truncate(before);
+    messages_before.reverse();
+    messages_after.sort_by_key(|msg| msg.timestamp);
+    messages_after.truncate(after);
+
+    Ok(Json(MessageContext {
+        messages_before: messages_before.into_iter().map(Into::into).collect(),
+        message: message.into(),
+        messages_after: messages_after.into_iter().map(Into::into).collect(),
+    }))
+}
+
+fn messages(hits: Vec<luoxu_rs::search::SearchHit>) -> Vec<LuoxuMessage> {
+    hits.into_iter().map(|hit| hit.message).collect()
+}
+
+/// Add the messages not already in a list.
+fn merge(messages: &mut Vec<LuoxuMessage>, other: Vec<LuoxuMessage>) {
+    for msg in other {
+        if !messages.iter().any(|m| m.event_id == msg.event_id) {
+            messages.push(msg);
+        }
+    }
+}
+
+/// Get the messages around a message from the homeserver, with the session of the bot.
+///
+/// Encrypted messages are skipped, as only the bot can decrypt them.
+async fn room_context(
+    state: &AppState,
+    message: &LuoxuMessage,
+    limit: usize,
+) -> anyhow::Result<(Vec<LuoxuMessage>, Vec<LuoxuMessage>)> {
+    let session = luoxu_rs::get_session()?;
+    let mut url = state.homeserver.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("Invalid homeserver URL"))?
+        .pop_if_empty()
+        .extend([
+            "_matrix",
+            "client",
+            "v3",
+            "rooms",
+            message.room_id.as_str(),
+            "context",
+            &message.event_id.event_id(),
+        ]);
+    url.query_pairs_mut()
+        .append_pair("limit", &limit.to_string());
+    let response = state
+        .http
+        .get(url)
+        .header(AUTHORIZATION, format!("Bearer {}", session.access_token))
+        .send()
+        .await?
+        .error_for_status()?;
+    let response: RoomContext = serde_json::from_slice(&response.bytes().await?)?;
+    let convert = |events: Vec<Raw<AnyTimelineEvent>>| -> Vec<LuoxuMessage> {
+        events
+            .into_iter()
+            .filter_map(|event| room_message(event, &session.user_id))
+            .collect()
+    };
+    Ok((
+        convert(response.events_before),
+        convert(response.events_after),
+    ))
+}
+
+/// Convert a message event of the room, skipping edits and messages of the bot.
+fn room_message(event: Raw<AnyTimelineEvent>, bot: &UserId) -> Option<LuoxuMessage> {
+    let ev = match event.deserialize() {
+        Ok(AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+            MessageLikeEvent::Original(ev),
+        ))) => ev,
+        _ => return None,
+    };
+    if ev.sender == bot || matches!(ev.content.relates_to, Some(Relation::Replacement(_))) {
+        return None;
+    }
+    Some(LuoxuMessage {
+        event_id: ev.event_id.into(),
+        body: message_body(ev.content)?,
+        external_url: None,
+        user_id: ev.sender,
+        user_display_name: None,
+        user_avatar: None,
+        timestamp: ev.origin_server_ts,
+        room_id: ev.room_id,
+        ocr_body: None,
+        edited_at: None,
+        normalized_body: None,
+        has: vec![],
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ContextParams {
+    before: Option<usize>,
+    after: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RoomContext {
+    #[serde(default)]
+    events_before: Vec<Raw<AnyTimelineEvent>>,
+    #[serde(default)]
+    events_after: Vec<Raw<AnyTimelineEvent>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageContext {
+    /// Messages sent before the message, oldest first.
+    pub messages_before: Vec<MessageSearchResult>,
+    pub message: MessageSearchResult,
+    /// Messages sent after the message, oldest first.
+    pub messages_after: Vec<MessageSearchResult>,
+}
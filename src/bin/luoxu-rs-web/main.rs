@@ -1,25 +1,50 @@
 #![forbid(unsafe_code)]
+pub mod auth;
+pub mod context;
+pub mod matrix;
 pub mod routes;
+pub mod ui;
 
-use axum::{routing::get, Router};
-use luoxu_rs::LuoxuConfig;
+use axum::{
+    extract::FromRef,
+    routing::{get, post},
+    Router,
+};
+use luoxu_rs::{LuoxuBotContext, LuoxuConfig};
+use matrix_sdk::reqwest::{self, Url};
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::signal;
 
-use crate::routes::{group_search, groups, index};
+use crate::auth::Sessions;
+use crate::routes::{group_search, groups, help, message_history, search};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
     let config = LuoxuConfig::get_config()?;
     let context = config.get_context()?;
+    let state = AppState {
+        context: context.into(),
+        homeserver: Url::parse(&config.matrix.homeserver_url)?,
+        http: reqwest::Client::new(),
+        sessions: Sessions::default(),
+    };
 
     // build our application with a single route
     let app = Router::new()
-        .route("/", get(index))
+        .route("/", get(ui::index))
+        .route("/app.js", get(ui::script))
+        .route("/style.css", get(ui::style))
+        .route("/help", get(help))
+        .route("/login", post(auth::login))
         .route("/groups", get(groups))
+        .route("/search", get(search))
         .route("/search/:index_name", get(group_search))
-        .with_state(context.into());
+        .route("/history/:index_name/:event_id", get(message_history))
+        .route("/context/:index_name/:event_id", get(context::context))
+        .route("/_matrix/client/v3/search", post(matrix::search))
+        .with_state(state);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
     tracing::info!("Listening on {}", addr);
@@ -32,6 +57,28 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[derive(Clone)]
+pub struct AppState {
+    pub context: Arc<LuoxuBotContext>,
+    /// The homeserver used to authenticate Matrix clients.
+    pub homeserver: Url,
+    pub http: reqwest::Client,
+    /// Sessions of users logged in with `/login`.
+    pub sessions: Sessions,
+}
+
+impl FromRef<AppState> for Arc<LuoxuBotContext> {
+    fn from_ref(state: &AppState) -> Self {
+        state.context.clone()
+    }
+}
+
+impl FromRef<AppState> for Sessions {
+    fn from_ref(state: &AppState) -> Self {
+        state.sessions.clone()
+    }
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
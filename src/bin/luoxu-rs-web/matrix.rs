@@ -0,0 +1,308 @@
+//! An implementation of the Matrix client-server search API.
+//!
+//! See <https://spec.matrix.org/v1.8/client-server-api/#post_matrixclientv3search>.
+
+use axum::{
+    extract::{Query, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use luoxu_rs::search::{ParsedQuery, SearchSort};
+use luoxu_rs::{ContentKind, LuoxuMessage};
+use ruma::OwnedRoomId;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+
+use crate::AppState;
+
+/// Default number of results per batch.
+const DEFAULT_LIMIT: usize = 10;
+/// Maximum number of results per batch.
+const MAX_LIMIT: usize = 100;
+/// `unsigned` key flagging events rebuilt from the index rather than served by the homeserver.
+const SYNTHETIC_KEY: &str = "rs.luoxu.synthetic";
+
+type MatrixResult<T> = Result<T, MatrixError>;
+
+/// Search the indexed rooms the requesting user is joined to.
+/// POST /_matrix/client/v3/search
+pub async fn search(
+    State(state): State<AppState>,
+    Query(params): Query<SearchParams>,
+    headers: HeaderMap,
+    Json(request): Json<SearchRequest>,
+) -> MatrixResult<Json<SearchResponse>> {
+    let criteria = match request.search_categories.room_events {
+        Some(criteria) => criteria,
+        None => return Ok(Json(SearchResponse::default())),
+    };
+    let offset = match &params.next_batch {
+        Some(next_batch) => next_batch.parse::<usize>().map_err(|_| {
+            MatrixError::new(
+                StatusCode::BAD_REQUEST,
+                "M_INVALID_PARAM",
+                "Invalid next_batch",
+            )
+        })?,
+        None => 0,
+    };
+    let parsed = ParsedQuery::parse(&criteria.search_term)
+        .map_err(|e| MatrixError::new(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", e.to_string()))?;
+    let filter = criteria.filter.unwrap_or_default();
+    let limit = filter.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+
+    let joined = joined_rooms(&state, &headers).await?;
+    let rooms: Vec<_> = state
+        .context
+        .store
+        .get_rooms()?
+        .into_iter()
+        .filter(|info| joined.contains(&info.room_id))
+        .filter(|info| match &filter.rooms {
+            Some(rooms) => rooms.contains(&info.room_id),
+            None => true,
+        })
+        .filter(|info| !filter.not_rooms.contains(&info.room_id))
+        .filter(|info| parsed.allows_index(&info.index_name))
+        .collect();
+    if rooms.is_empty() {
+        return Ok(Json(SearchResponse::default()));
+    }
+
+    // Fetch enough hits from every index to merge them.
+    let sort = match criteria.order_by {
+        OrderBy::Rank => SearchSort::Relevance,
+        OrderBy::Recent => SearchSort::Newest,
+    };
+    let mut queries = Vec::new();
+    for info in rooms {
+        let mut query = parsed.search_query(&state.context.normalizer, &info.index_name);
+        // Rooms sharing an index are searched separately.
+        if state.context.store.is_shared_index(&info.index_name)? {
+            query.filter.rooms = vec![OwnedRoomId::try_from(info.room_id)?];
+        }
+        query.sort = sort;
+        query.limit = offset + limit;
+        queries.push((info.index_name, query));
+    }
+    let response = state.context.search.multi_search(&queries).await?;
+
+    let count = response
+        .iter()
+        .map(|result| result.estimated_total_hits)
+        .sum();
+    let mut hits: Vec<_> = response
+        .into_iter()
+        .flat_map(|result| result.hits)
+        .map(|hit| (hit.score.unwrap_or(0.0), hit.message))
+        .collect();
+    match criteria.order_by {
+        OrderBy::Rank => hits.sort_by(|(a, _), (b, _)| b.total_cmp(a)),
+        OrderBy::Recent => hits.sort_by_key(|(_, msg)| Reverse(msg.timestamp)),
+    }
+    let results: Vec<_> = hits
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|(rank, msg)| SearchResultItem {
+            rank,
+            result: client_event(msg),
+        })
+        .collect();
+    let next_batch = if offset + limit < count {
+        Some((offset + limit).to_string())
+    } else {
+        None
+    };
+    Ok(Json(SearchResponse {
+        search_categories: ResultCategories {
+            room_events: RoomEventsResults {
+                count,
+                highlights: parsed
+                    .text
+                    .split(|c: char| c.is_whitespace() || c == '"')
+                    .filter(|word| !word.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                next_batch,
+                results,
+            },
+        },
+    }))
+}
+
+/// Get the rooms the user owning the access token is joined to.
+async fn joined_rooms(state: &AppState, headers: &HeaderMap) -> MatrixResult<HashSet<String>> {
+    let authorization = headers.get(AUTHORIZATION).ok_or_else(|| {
+        MatrixError::new(
+            StatusCode::UNAUTHORIZED,
+            "M_MISSING_TOKEN",
+            "Missing access token",
+        )
+    })?;
+    let url = state.homeserver.join("/_matrix/client/v3/joined_rooms")?;
+    let response = state
+        .http
+        .get(url)
+        .header(AUTHORIZATION, authorization)
+        .send()
+        .await?;
+    if response.status() == StatusCode::UNAUTHORIZED {
+        return Err(MatrixError::new(
+            StatusCode::UNAUTHORIZED,
+            "M_UNKNOWN_TOKEN",
+            "Unrecognised access token",
+        ));
+    }
+    let response: JoinedRooms =
+        serde_json::from_slice(&response.error_for_status()?.bytes().await?)?;
+    Ok(response.joined_rooms.into_iter().collect())
+}
+
+/// Build a client event from an indexed message.
+///
+/// Only the indexed body is known, so media messages lack their file and the event is flagged
+/// as synthetic in `unsigned`, for clients to fetch the real event.
+fn client_event(msg: LuoxuMessage) -> Value {
+    let msgtype = msg
+        .has
+        .iter()
+        .find_map(|kind| match kind {
+            ContentKind::Image => Some("m.image"),
+            ContentKind::File => Some("m.file"),
+            ContentKind::Video => Some("m.video"),
+            ContentKind::Link => None,
+        })
+        .unwrap_or("m.text");
+    let mut content = json!({
+        "msgtype": msgtype,
+        "body": msg.body,
+    });
+    if let Some(external_url) = msg.external_url {
+        content["external_url"] = external_url.into();
+    }
+    json!({
+        "type": "m.room.message",
+        "content": content,
+        "event_id": msg.event_id.event_id(),
+        "origin_server_ts": msg.timestamp,
+        "room_id": msg.room_id,
+        "sender": msg.user_id,
+        "unsigned": { SYNTHETIC_KEY: true },
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    next_batch: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchRequest {
+    search_categories: Categories,
+}
+
+#[derive(Debug, Deserialize)]
+struct Categories {
+    room_events: Option<RoomEventsCriteria>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RoomEventsCriteria {
+    search_term: String,
+    filter: Option<RoomEventFilter>,
+    #[serde(default)]
+    order_by: OrderBy,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RoomEventFilter {
+    limit: Option<usize>,
+    rooms: Option<Vec<String>>,
+    #[serde(default)]
+    not_rooms: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum OrderBy {
+    #[default]
+    Rank,
+    Recent,
+}
+
+#[derive(Debug, Deserialize)]
+struct JoinedRooms {
+    joined_rooms: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SearchResponse {
+    search_categories: ResultCategories,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ResultCategories {
+    room_events: RoomEventsResults,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct RoomEventsResults {
+    count: usize,
+    highlights: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_batch: Option<String>,
+    results: Vec<SearchResultItem>,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResultItem {
+    rank: f64,
+    result: Value,
+}
+
+/// An error in the format of the Matrix specification.
+pub struct MatrixError {
+    status: StatusCode,
+    errcode: &'static str,
+    error: String,
+}
+
+impl MatrixError {
+    pub fn new(status: StatusCode, errcode: &'static str, error: impl Into<String>) -> Self {
+        Self {
+            status,
+            errcode,
+            error: error.into(),
+        }
+    }
+}
+
+impl IntoResponse for MatrixError {
+    fn into_response(self) -> Response {
+        (
+            self.status,
+            Json(json!({
+                "errcode": self.errcode,
+                "error": self.error,
+            })),
+        )
+            .into_response()
+    }
+}
+
+impl<E> From<E> for MatrixError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "M_UNKNOWN",
+            err.into().to_string(),
+        )
+    }
+}
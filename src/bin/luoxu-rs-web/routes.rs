@@ -4,103 +4,376 @@ use axum::{
     response::{IntoResponse, Response},
     Json,
 };
-use luoxu_rs::{LuoxuBotContext, LuoxuMessage, RoomInfo};
-use meilisearch_sdk::Selectors;
-use ruma::MilliSecondsSinceUnixEpoch;
+use luoxu_rs::search::{ParsedQuery, SearchHit, SearchSort, DEFAULT_LIMIT};
+use luoxu_rs::{KeyEventId, LuoxuBotContext, LuoxuMessage, RoomInfo};
+use ruma::{MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, UInt, UserId};
 use serde::Deserialize;
 use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
-type RouteResult<T> = Result<T, AppError>;
+use crate::auth::AuthUser;
 
-pub async fn index() -> &'static str {
+pub type RouteResult<T> = Result<T, AppError>;
+
+/// Tags around matched words in `html_body`.
+const HIGHLIGHT_PRE_TAG: &str = "<span class=\"keyword\">";
+const HIGHLIGHT_POST_TAG: &str = "</span>";
+/// Largest number of results skipped when paging by relevance.
+const MAX_RELEVANCE_OFFSET: usize = 1000;
+
+pub async fn help() -> &'static str {
     "\
 Luoxu-rs Web interface
 
+- GET /
+ The search UI.
+
+- POST /login
+ Log in with a Matrix OpenID token, as returned by /_matrix/client/v3/user/{userId}/openid/request_token.
+ Returns {\"token\": \"...\"}, to be sent as `Authorization: Bearer <token>` to the routes below,
+ which only give access to the indexed rooms the user is joined to.
+
 - GET /groups
- Returns a list of indexed rooms.
+ Returns a list of indexed rooms the user is joined to.
+ An upgraded room is listed once as the latest room the user is joined to, with the IDs of the rooms it
+ replaced in `predecessors`, whose messages are searched with it. Rooms replacing it stay hidden.
+ Rooms the bot is no longer in have an `inactive` object with the reason (left, kicked or banned),
+ the message given when removing the bot and the time, their messages are no longer updated.
 
 - GET /search/:index_name?query=(query)[&offset=offset]
  Search an index.
 
  Parameters:
   - [Required] index_name: The index name.
-  - [Required] query: The query paramter.
+  - [Required] query: The query paramter, which can contain these operators:
+    - from:@user:example.org      Messages sent by a user.
+    - before:YYYY-MM-DD           Messages sent before a day (UTC).
+    - after:YYYY-MM-DD            Messages sent after a day (UTC).
+    - has:image|file|video|link   Messages with this kind of content.
+    - in:index                    Messages in an index.
+    - \"quoted phrase\"             Messages containing the exact phrase.
   - [Optional] offset: The server timestamp offset, should be specified as miliseconds since Unix epoch.
+
+- GET /search?query=(query)[&indices=a,b,c][&sort=newest|oldest|relevance][&offset=offset]
+ Search several indices at once, labelling each message with its index and room name.
+
+ Parameters:
+  - [Required] query: The query paramter, with the same operators as above.
+  - [Optional] indices: Comma separated index names, all indices are searched by default.
+  - [Optional] sort: Order of the merged results, newest (default), oldest or relevance.
+  - [Optional] offset: Where the page starts. For newest and oldest, the timestamp of the last message of the
+    previous page in miliseconds since Unix epoch, only older or newer messages are returned.
+    For relevance, the number of messages to skip, up to 1000.
+ `has_more` is true when another page follows.
+
+- GET /history/:index_name/:event_id
+ Returns the edit history of a message.
+
+ Parameters:
+  - [Required] index_name: The index name.
+  - [Required] event_id: The event ID of the original message, the leading `$` can be omitted.
+
+- GET /context/:index_name/:event_id[?before=N][&after=N]
+ Returns the messages sent around a message, oldest first.
+ Messages missing from the index are fetched from the room when the bot can read them.
+
+ Parameters:
+  - [Required] index_name: The index name.
+  - [Required] event_id: The event ID of the message, the leading `$` can be omitted.
+  - [Optional] before: The number of messages before it, 5 by default and at most 50.
+  - [Optional] after: The number of messages after it, 5 by default and at most 50.
+
+- POST /_matrix/client/v3/search
+ The Matrix client-server search API, searching indexed rooms the user is joined to.
+ Only the room_events category is supported.
 "
 }
 
-/// List all groups indexed.
-pub async fn groups(State(state): State<Arc<LuoxuBotContext>>) -> RouteResult<Json<Vec<RoomInfo>>> {
-    let result = state.store.get_rooms()?;
+/// List the indexed groups the user is joined to.
+pub async fn groups(
+    State(state): State<Arc<LuoxuBotContext>>,
+    AuthUser(user_id): AuthUser,
+) -> RouteResult<Json<Vec<RoomInfo>>> {
+    let rooms = state.store.get_rooms()?;
+    let room_ids: HashSet<_> = rooms.iter().map(|info| info.room_id.as_str()).collect();
+    let successors: HashMap<_, _> = rooms
+        .iter()
+        .filter_map(|info| Some((info.room_id.as_str(), info.successor.as_deref()?)))
+        .filter(|(_, successor)| room_ids.contains(successor))
+        .collect();
+    let mut result = Vec::new();
+    for info in &rooms {
+        // Members of a room see it with the history of the rooms it replaced, never the rooms
+        // replacing it.
+        if !state.store.is_member(&info.room_id, &user_id)? {
+            continue;
+        }
+        // An upgraded room is listed once, as the latest room of its chain the user is in.
+        let mut chain = HashSet::from([info.room_id.as_str()]);
+        let mut later = false;
+        let mut room_id = info.room_id.as_str();
+        while let Some(successor) = successors.get(room_id).copied() {
+            // Guard against upgrade loops.
+            if !chain.insert(successor) {
+                break;
+            }
+            if state.store.is_member(successor, &user_id)? {
+                later = true;
+                break;
+            }
+            room_id = successor;
+        }
+        if !later {
+            result.push(info.clone());
+        }
+    }
     Ok(Json(result))
 }
 
+/// Get the rooms of the index the user can see as a search filter, failing unless they see any.
+pub fn check_member(
+    state: &LuoxuBotContext,
+    user_id: &UserId,
+    index_name: &str,
+) -> RouteResult<Vec<OwnedRoomId>> {
+    state
+        .store
+        .get_room_filter(user_id, index_name)?
+        .ok_or_else(|| AppError::new(StatusCode::FORBIDDEN, "You are not a member of this group"))
+}
+
+/// Fail unless a message is in one of the rooms of a filter.
+pub fn check_visible(rooms: &[OwnedRoomId], message: &LuoxuMessage) -> RouteResult<()> {
+    if !rooms.is_empty() && !rooms.contains(&message.room_id) {
+        return Err(AppError::new(StatusCode::NOT_FOUND, "Message not found"));
+    }
+    Ok(())
+}
+
 /// Search a group.
 /// GET /search/:index_name?query=
 pub async fn group_search(
     State(state): State<Arc<LuoxuBotContext>>,
+    AuthUser(user_id): AuthUser,
     Path(index_name): Path<String>,
     Query(params): Query<Params>,
 ) -> RouteResult<Json<MessageSearchResults>> {
-    #[allow(unused_assignments)]
-    let mut filter: String = "".to_string();
-    let index = state.search.index(index_name);
-    let mut query = index.search();
-    let mut query = query
-        .with_query(&params.query)
-        .with_sort(&["timestamp:desc"])
-        .with_attributes_to_search_on(&["body"])
-        .with_attributes_to_highlight(Selectors::Some(&["body"]))
-        .with_highlight_pre_tag("<span class=\"keyword\"")
-        .with_highlight_post_tag("</span>");
+    let rooms = check_member(&state, &user_id, &index_name)?;
+    let parsed = ParsedQuery::parse(&params.query)
+        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, e.to_string()))?;
+    if !parsed.allows_index(&index_name) {
+        return Ok(Json(MessageSearchResults {
+            messages: vec![],
+            has_more: false,
+        }));
+    }
+    let mut query = parsed.search_query(&state.normalizer, &index_name);
+    query.filter.rooms = rooms;
     if let Some(offset) = params.offset {
-        let offset = offset.0;
-        filter = format!("timestamp < {}", offset);
-        query = query.with_filter(&filter);
+        query.filter.before = Some(query.filter.before.map_or(offset, |b| b.min(offset)));
     }
-    let search_result = query.execute::<LuoxuMessage>().await?;
+    query.highlight = Some((HIGHLIGHT_PRE_TAG.into(), HIGHLIGHT_POST_TAG.into()));
+    let search_result = state.search.search(&index_name, &query).await?;
     let result = MessageSearchResults {
         messages: search_result
             .hits
+            .into_iter()
+            .map(MessageSearchResult::from)
+            .collect(),
+        has_more: search_result.estimated_total_hits > query.limit,
+    };
+    Ok(Json(result))
+}
+
+/// Search several groups, merging the results.
+/// GET /search?query=[&indices=a,b,c]
+pub async fn search(
+    State(state): State<Arc<LuoxuBotContext>>,
+    AuthUser(user_id): AuthUser,
+    Query(params): Query<MultiParams>,
+) -> RouteResult<Json<RoomMessageSearchResults>> {
+    let parsed = ParsedQuery::parse(&params.query)
+        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, e.to_string()))?;
+    let rooms = state.store.get_rooms()?;
+    // Only the indices of groups the user is joined to are known to them.
+    let mut indices: Vec<_> = state
+        .store
+        .get_member_indices(&user_id)?
+        .into_iter()
+        .collect();
+    indices.sort();
+    if let Some(requested) = &params.indices {
+        let requested: Vec<_> = requested
+            .split(',')
+            .map(str::trim)
+            .filter(|index| !index.is_empty())
+            .collect();
+        if let Some(unknown) = requested
             .iter()
-            .map(|item| {
-                let result = item.result.clone();
-                let formatted_result = item.formatted_result.as_ref().unwrap();
-                let highlight_body = formatted_result.get("body").unwrap();
-                MessageSearchResult {
-                    event_id: result.event_id.event_id(),
-                    html_body: highlight_body.to_string(),
-                    external_url: result.external_url,
-                    display_name: result.user_display_name,
-                    timestamp: result.timestamp,
-                    room_id: result.room_id.to_string(),
-                    avatar_url: result.user_avatar.map(|result| result.into_string()),
+            .find(|index| !indices.contains(&index.to_string()))
+        {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Unknown index: {}", unknown),
+            ));
+        }
+        indices.retain(|index| requested.contains(&index.as_str()));
+    }
+    indices.retain(|index| parsed.allows_index(index));
+
+    let sort = params.sort.unwrap_or_default();
+    // Results ranked by relevance are paged by position, the others by timestamp.
+    let skip = match (sort, params.offset) {
+        (SearchSort::Relevance, Some(offset)) => usize::try_from(u64::from(offset))
+            .ok()
+            .filter(|offset| *offset <= MAX_RELEVANCE_OFFSET)
+            .ok_or_else(|| AppError::new(StatusCode::BAD_REQUEST, "Invalid offset"))?,
+        _ => 0,
+    };
+    let mut queries = Vec::new();
+    for index in indices {
+        let mut query = parsed.search_query(&state.normalizer, &index);
+        query.filter.rooms = check_member(&state, &user_id, &index)?;
+        match (sort, params.offset.map(MilliSecondsSinceUnixEpoch)) {
+            (SearchSort::Newest, Some(offset)) => {
+                query.filter.before = Some(query.filter.before.map_or(offset, |b| b.min(offset)));
+            }
+            (SearchSort::Oldest, Some(offset)) => {
+                query.filter.after = Some(query.filter.after.map_or(offset, |a| a.max(offset)));
+            }
+            _ => {}
+        }
+        // One more hit than shown tells whether there are more.
+        query.limit = skip + DEFAULT_LIMIT + 1;
+        query.sort = sort;
+        query.highlight = Some((HIGHLIGHT_PRE_TAG.into(), HIGHLIGHT_POST_TAG.into()));
+        queries.push((index, query));
+    }
+    let results = state.search.multi_search(&queries).await?;
+
+    let mut hits: Vec<_> = queries
+        .iter()
+        .zip(results)
+        .flat_map(|((index, _), result)| result.hits.into_iter().map(move |hit| (index, hit)))
+        .collect();
+    match sort {
+        SearchSort::Newest => hits.sort_by_key(|(_, hit)| Reverse(hit.message.timestamp)),
+        SearchSort::Oldest => hits.sort_by_key(|(_, hit)| hit.message.timestamp),
+        SearchSort::Relevance => {
+            hits.sort_by(|(_, a), (_, b)| b.score.unwrap_or(0.0).total_cmp(&a.score.unwrap_or(0.0)))
+        }
+    }
+    let has_more = hits.len() > skip + DEFAULT_LIMIT;
+    let hits: Vec<_> = hits.into_iter().skip(skip).take(DEFAULT_LIMIT).collect();
+
+    let room_names: HashMap<_, _> = rooms
+        .into_iter()
+        .map(|info| (info.room_id, info.room_name))
+        .collect();
+    let result = RoomMessageSearchResults {
+        has_more,
+        messages: hits
+            .into_iter()
+            .map(|(index, hit)| {
+                let room_name = room_names
+                    .get(hit.message.room_id.as_str())
+                    .cloned()
+                    .flatten();
+                RoomMessageSearchResult {
+                    index_name: index.clone(),
+                    room_name,
+                    message: hit.into(),
                 }
             })
             .collect(),
-        has_more: search_result.estimated_total_hits.unwrap() > search_result.limit.unwrap(),
     };
     Ok(Json(result))
 }
 
+/// Get the edit history of a message.
+/// GET /history/:index_name/:event_id
+pub async fn message_history(
+    State(state): State<Arc<LuoxuBotContext>>,
+    AuthUser(user_id): AuthUser,
+    Path((index_name, event_id)): Path<(String, String)>,
+) -> RouteResult<Json<MessageEditHistory>> {
+    let rooms = check_member(&state, &user_id, &index_name)?;
+    let event_id: KeyEventId = parse_event_id(event_id)?.into();
+    let message = state
+        .search
+        .get(&index_name, &event_id)
+        .await?
+        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, "Message not found"))?;
+    check_visible(&rooms, &message)?;
+    let edits = state
+        .store
+        .get_edits(&event_id.event_id())?
+        .into_iter()
+        .filter(|edit| edit.user_id == message.user_id)
+        .map(|edit| MessageEdit {
+            event_id: edit.event_id,
+            body: edit.body,
+            timestamp: edit.timestamp,
+        })
+        .collect();
+    Ok(Json(MessageEditHistory {
+        event_id: event_id.event_id(),
+        body: message.body,
+        timestamp: message.timestamp,
+        edited_at: message.edited_at,
+        edits,
+    }))
+}
+
+/// Parse an event ID from a path, where the leading `$` can be omitted.
+pub fn parse_event_id(event_id: String) -> RouteResult<OwnedEventId> {
+    let event_id = if event_id.starts_with('$') {
+        event_id
+    } else {
+        format!("${}", event_id)
+    };
+    OwnedEventId::try_from(event_id)
+        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, format!("Invalid event ID: {}", e)))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Params {
     query: String,
     offset: Option<MilliSecondsSinceUnixEpoch>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct MultiParams {
+    query: String,
+    /// Comma separated index names, all indices when missing.
+    indices: Option<String>,
+    sort: Option<SearchSort>,
+    /// The timestamp to continue from, or the number of results to skip by relevance.
+    offset: Option<UInt>,
+}
+
 // Make our own error that wraps `anyhow::Error`.
-pub struct AppError(anyhow::Error);
+pub struct AppError(StatusCode, anyhow::Error);
+
+impl AppError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self(status, anyhow::anyhow!(message.into()))
+    }
+}
 
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+        match self.0 {
+            StatusCode::INTERNAL_SERVER_ERROR => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Something went wrong: {}", self.1),
+            )
+                .into_response(),
+            status => (status, self.1.to_string()).into_response(),
+        }
     }
 }
 
@@ -111,7 +384,7 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        Self(StatusCode::INTERNAL_SERVER_ERROR, err.into())
     }
 }
 
@@ -125,9 +398,72 @@ pub struct MessageSearchResults {
 pub struct MessageSearchResult {
     pub event_id: String, // Primary
     pub html_body: String,
+    /// Text recognized in an image.
+    pub html_ocr_body: Option<String>,
     pub external_url: Option<String>,
     pub display_name: Option<String>,
     pub avatar_url: Option<String>,
     pub timestamp: MilliSecondsSinceUnixEpoch,
+    pub edited_at: Option<MilliSecondsSinceUnixEpoch>,
     pub room_id: String,
 }
+
+impl From<LuoxuMessage> for MessageSearchResult {
+    fn from(result: LuoxuMessage) -> Self {
+        MessageSearchResult {
+            event_id: result.event_id.event_id(),
+            html_body: result.body,
+            html_ocr_body: result.ocr_body,
+            external_url: result.external_url,
+            display_name: result.user_display_name,
+            timestamp: result.timestamp,
+            edited_at: result.edited_at,
+            room_id: result.room_id.to_string(),
+            avatar_url: result.user_avatar.map(|result| result.into_string()),
+        }
+    }
+}
+
+impl From<SearchHit> for MessageSearchResult {
+    fn from(hit: SearchHit) -> Self {
+        let mut result = MessageSearchResult::from(hit.message);
+        if let Some(body) = hit.highlighted_body {
+            result.html_body = body;
+        }
+        if let Some(ocr_body) = hit.highlighted_ocr_body {
+            result.html_ocr_body = Some(ocr_body);
+        }
+        result
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomMessageSearchResults {
+    pub messages: Vec<RoomMessageSearchResult>,
+    pub has_more: bool,
+}
+
+/// A search result labelled with the room it was found in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomMessageSearchResult {
+    #[serde(flatten)]
+    pub message: MessageSearchResult,
+    pub index_name: String,
+    pub room_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageEditHistory {
+    pub event_id: String,
+    pub body: String,
+    pub timestamp: MilliSecondsSinceUnixEpoch,
+    pub edited_at: Option<MilliSecondsSinceUnixEpoch>,
+    pub edits: Vec<MessageEdit>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageEdit {
+    pub event_id: String,
+    pub body: String,
+    pub timestamp: MilliSecondsSinceUnixEpoch,
+}
@@ -0,0 +1,325 @@
+"use strict";
+
+const TOKEN_KEY = "luoxu-token";
+const HIGHLIGHT_PRE_TAG = '<span class="keyword">';
+const HIGHLIGHT_POST_TAG = "</span>";
+
+const $ = (id) => document.getElementById(id);
+
+const search = {
+  group: "",
+  query: "",
+  offset: null,
+  hasMore: false,
+  loading: false,
+  // Bumped on every new search, to drop results of older ones.
+  generation: 0,
+};
+
+class Unauthorized extends Error {}
+
+// Call the luoxu-rs-web API with the session token.
+async function api(path, options = {}) {
+  const headers = { ...options.headers };
+  const token = localStorage.getItem(TOKEN_KEY);
+  if (token) {
+    headers.Authorization = `Bearer ${token}`;
+  }
+  const response = await fetch(path, { ...options, headers });
+  if (response.status === 401) {
+    localStorage.removeItem(TOKEN_KEY);
+    showLogin();
+    throw new Unauthorized(await response.text());
+  }
+  if (!response.ok) {
+    throw new Error(await response.text());
+  }
+  return response.json();
+}
+
+// Call the client-server API of a homeserver.
+async function matrix(homeserver, path, accessToken, body) {
+  const headers = { "Content-Type": "application/json" };
+  if (accessToken) {
+    headers.Authorization = `Bearer ${accessToken}`;
+  }
+  const response = await fetch(new URL(path, homeserver), {
+    method: "POST",
+    headers,
+    body: JSON.stringify(body),
+  });
+  const json = await response.json();
+  if (!response.ok) {
+    throw new Error(json.error || `${response.status} ${response.statusText}`);
+  }
+  return json;
+}
+
+// Log in to the homeserver only long enough to get an OpenID token for luoxu-rs-web.
+async function login(homeserver, username, password) {
+  const session = await matrix(homeserver, "/_matrix/client/v3/login", null, {
+    type: "m.login.password",
+    identifier: { type: "m.id.user", user: username },
+    password,
+    initial_device_display_name: "Luoxu Web",
+  });
+  try {
+    const openid = await matrix(
+      homeserver,
+      `/_matrix/client/v3/user/${encodeURIComponent(session.user_id)}/openid/request_token`,
+      session.access_token,
+      {},
+    );
+    const { token } = await api("/login", {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify(openid),
+    });
+    localStorage.setItem(TOKEN_KEY, token);
+  } finally {
+    matrix(homeserver, "/_matrix/client/v3/logout", session.access_token, {}).catch(() => {});
+  }
+}
+
+function showLogin() {
+  $("login").hidden = false;
+  $("search").hidden = true;
+  $("logout").hidden = true;
+  $("results").replaceChildren();
+  $("status").textContent = "";
+}
+
+async function showSearch() {
+  $("login").hidden = true;
+  $("search").hidden = false;
+  $("logout").hidden = false;
+  const groups = await api("/groups");
+  const select = $("search").elements.group;
+  select.replaceChildren(select.options[0]);
+  const seen = new Set();
+  for (const group of groups) {
+    if (seen.has(group.index_name)) {
+      continue;
+    }
+    seen.add(group.index_name);
+    const name = group.room_name || group.index_name;
+    select.append(new Option(group.inactive ? `${name} (inactive)` : name, group.index_name));
+  }
+}
+
+function escapeHtml(text) {
+  const div = document.createElement("div");
+  div.textContent = text;
+  return div.innerHTML;
+}
+
+// Escape a highlighted body, keeping only the highlight tags.
+function highlightedHtml(html) {
+  return escapeHtml(html)
+    .replaceAll(escapeHtml(HIGHLIGHT_PRE_TAG), HIGHLIGHT_PRE_TAG)
+    .replaceAll(escapeHtml(HIGHLIGHT_POST_TAG), HIGHLIGHT_POST_TAG);
+}
+
+function permalink(message) {
+  return `https://matrix.to/#/${encodeURIComponent(message.room_id)}/${encodeURIComponent(message.event_id)}`;
+}
+
+function element(tag, className, text) {
+  const el = document.createElement(tag);
+  if (className) {
+    el.className = className;
+  }
+  if (text !== undefined) {
+    el.textContent = text;
+  }
+  return el;
+}
+
+function renderMessage(message, indexName) {
+  const item = element("li", "message");
+
+  const avatar = element("div", "avatar");
+  if (message.avatar_url) {
+    const img = document.createElement("img");
+    img.src = message.avatar_url;
+    img.alt = "";
+    img.loading = "lazy";
+    avatar.append(img);
+  } else {
+    avatar.textContent = (message.display_name || "?").charAt(0).toUpperCase();
+  }
+  item.append(avatar);
+
+  const content = element("div");
+  const meta = element("div", "meta");
+  meta.append(element("span", "name", message.display_name || ""));
+  if (message.room_name !== undefined) {
+    meta.append(element("span", "room", message.room_name || message.index_name));
+  }
+  const time = element("time", "", new Date(message.timestamp).toLocaleString());
+  time.dateTime = new Date(message.timestamp).toISOString();
+  meta.append(time);
+  if (message.edited_at) {
+    meta.append(element("span", "", "(edited)"));
+  }
+  const link = element("a", "", "Open in Matrix");
+  link.href = permalink(message);
+  link.target = "_blank";
+  link.rel = "noopener";
+  meta.append(link);
+  if (indexName) {
+    const button = element("button", "", "Context");
+    button.type = "button";
+    button.addEventListener("click", () => toggleContext(item, indexName, message, button));
+    meta.append(button);
+  }
+  content.append(meta);
+
+  const body = element("div", "body");
+  body.innerHTML = highlightedHtml(message.html_body);
+  content.append(body);
+  if (message.html_ocr_body) {
+    const ocr = element("div", "ocr");
+    ocr.innerHTML = highlightedHtml(message.html_ocr_body);
+    content.append(ocr);
+  }
+  item.append(content);
+  return item;
+}
+
+async function toggleContext(item, indexName, message, button) {
+  const existing = item.querySelector(".context");
+  if (existing) {
+    existing.remove();
+    return;
+  }
+  button.disabled = true;
+  try {
+    const context = await api(
+      `/context/${encodeURIComponent(indexName)}/${encodeURIComponent(message.event_id)}`,
+    );
+    const list = element("ol", "context");
+    for (const msg of context.messages_before) {
+      list.append(renderMessage(msg));
+    }
+    const current = renderMessage(context.message);
+    current.classList.add("current");
+    list.append(current);
+    for (const msg of context.messages_after) {
+      list.append(renderMessage(msg));
+    }
+    item.append(list);
+  } catch (e) {
+    if (!(e instanceof Unauthorized)) {
+      $("search-error").textContent = e.message;
+    }
+  } finally {
+    button.disabled = false;
+  }
+}
+
+async function loadMore() {
+  if (search.loading) {
+    return;
+  }
+  const generation = search.generation;
+  search.loading = true;
+  $("status").textContent = "Loading…";
+  const params = new URLSearchParams({ query: search.query });
+  if (search.offset !== null) {
+    params.set("offset", search.offset);
+  }
+  const path = search.group
+    ? `/search/${encodeURIComponent(search.group)}?${params}`
+    : `/search?${params}`;
+  try {
+    const result = await api(path);
+    if (generation !== search.generation) {
+      return;
+    }
+    for (const message of result.messages) {
+      $("results").append(renderMessage(message, search.group || message.index_name));
+    }
+    const last = result.messages[result.messages.length - 1];
+    search.hasMore = result.has_more && last !== undefined;
+    if (last !== undefined) {
+      search.offset = last.timestamp;
+    }
+    if (search.hasMore) {
+      $("status").textContent = "";
+    } else if ($("results").childElementCount === 0) {
+      $("status").textContent = "No results.";
+    } else {
+      $("status").textContent = "No more results.";
+    }
+  } catch (e) {
+    if (generation !== search.generation) {
+      return;
+    }
+    search.hasMore = false;
+    $("status").textContent = "";
+    if (!(e instanceof Unauthorized)) {
+      $("search-error").textContent = e.message;
+    }
+  } finally {
+    if (generation === search.generation) {
+      search.loading = false;
+    }
+  }
+  // Keep loading while the end of the list is still visible.
+  if (search.hasMore && isVisible($("sentinel"))) {
+    loadMore();
+  }
+}
+
+function isVisible(el) {
+  return el.getBoundingClientRect().top < window.innerHeight;
+}
+
+$("login").addEventListener("submit", async (event) => {
+  event.preventDefault();
+  const form = event.target;
+  $("login-error").textContent = "";
+  try {
+    await login(form.elements.homeserver.value, form.elements.username.value, form.elements.password.value);
+    form.elements.password.value = "";
+    await showSearch();
+  } catch (e) {
+    $("login-error").textContent = e.message;
+  }
+});
+
+$("search").addEventListener("submit", (event) => {
+  event.preventDefault();
+  const form = event.target;
+  search.group = form.elements.group.value;
+  search.query = form.elements.query.value;
+  search.offset = null;
+  search.hasMore = false;
+  search.loading = false;
+  search.generation += 1;
+  $("search-error").textContent = "";
+  $("results").replaceChildren();
+  loadMore();
+});
+
+$("logout").addEventListener("click", () => {
+  localStorage.removeItem(TOKEN_KEY);
+  showLogin();
+});
+
+new IntersectionObserver((entries) => {
+  if (entries.some((entry) => entry.isIntersecting) && search.hasMore) {
+    loadMore();
+  }
+}).observe($("sentinel"));
+
+if (localStorage.getItem(TOKEN_KEY)) {
+  showSearch().catch((e) => {
+    if (!(e instanceof Unauthorized)) {
+      $("search-error").textContent = e.message;
+    }
+  });
+} else {
+  showLogin();
+}
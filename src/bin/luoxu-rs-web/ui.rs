@@ -0,0 +1,24 @@
+//! The search UI, compiled into the binary.
+
+use axum::{http::header::CONTENT_TYPE, response::Html, response::IntoResponse};
+
+/// GET /
+pub async fn index() -> Html<&'static str> {
+    Html(include_str!("static/index.html"))
+}
+
+/// GET /app.js
+pub async fn script() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/javascript; charset=utf-8")],
+        include_str!("static/app.js"),
+    )
+}
+
+/// GET /style.css
+pub async fn style() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/css; charset=utf-8")],
+        include_str!("static/style.css"),
+    )
+}
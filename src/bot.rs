@@ -1,16 +1,31 @@
 use anyhow::bail;
 use luoxu_rs::LuoxuBotContext;
 use luoxu_rs::LuoxuConfig;
-use matrix_sdk::config::SyncSettings;
+use matrix_sdk::config::{StoreConfig, SyncSettings};
 use matrix_sdk::ruma::{RoomAliasId, RoomId};
+use matrix_sdk::LoopCtrl;
 use matrix_sdk::Session;
-use meilisearch_sdk::IndexesQuery;
+use matrix_sdk_sled::{SledCryptoStore, SledStateStore};
 
 use std::sync::Arc;
+use std::time::Duration;
 
+use crate::backfill::backfill_rooms;
+use crate::backfill::reconcile_redactions;
+use crate::backfill::{fill_gaps, handle_sync_response};
+use crate::callbacks::on_room_encrypted;
 use crate::callbacks::on_room_message;
 use crate::callbacks::on_room_name;
+use crate::callbacks::on_room_redaction;
 use crate::callbacks::on_room_tombstone;
+use crate::commands::{on_room_command, CommandSettings};
+use crate::encryption::{restore_backup, retry_undecrypted_loop};
+use crate::invites::{handle_pending_invites, on_stripped_member};
+use crate::members::{expire_inactive_loop, on_room_member, sync_members};
+use crate::ocr::ocr_loop;
+use crate::outbox::outbox_loop;
+use crate::spaces::{on_space_child, sync_spaces, SpaceSettings};
+use crate::upgrades::follow_upgrades;
 
 pub enum LoginType {
     Password(String),
@@ -20,15 +35,28 @@ pub enum LoginType {
 pub struct LuoxuBot {
     config: LuoxuConfig,
     client: matrix_sdk::Client,
+    /// The crypto store of the client, to import room keys from the key backup.
+    crypto_store: Arc<SledCryptoStore>,
     context: Arc<LuoxuBotContext>,
 }
 
 impl LuoxuBot {
     pub async fn new(config: LuoxuConfig) -> anyhow::Result<Self> {
         use matrix_sdk::Client;
+        let mut state_store = SledStateStore::builder();
+        state_store.path("store".into());
+        if let Some(passphrase) = &config.matrix.store_passphrase {
+            state_store.passphrase(passphrase.clone());
+        }
+        let state_store = state_store.build()?;
+        let crypto_store = Arc::new(state_store.open_crypto_store()?);
         let builder = Client::builder()
             .homeserver_url(&config.matrix.homeserver_url)
-            .sled_store("store", None)?;
+            .store_config(
+                StoreConfig::new()
+                    .state_store(state_store)
+                    .crypto_store(crypto_store.clone()),
+            );
         let client = builder.build().await?;
 
         let context = config.get_context()?;
@@ -36,10 +64,15 @@ impl LuoxuBot {
         Ok(LuoxuBot {
             config,
             client,
+            crypto_store,
             context: context.into(),
         })
     }
 
+    pub fn context(&self) -> Arc<LuoxuBotContext> {
+        self.context.clone()
+    }
+
     pub async fn login(&self, login: LoginType) -> anyhow::Result<Option<Session>> {
         match login {
             LoginType::Password(password) => {
@@ -59,32 +92,29 @@ impl LuoxuBot {
         }
     }
 
+    /// Create the indices and bring their settings up to date.
     pub async fn update_indices(&self) -> anyhow::Result<()> {
-        let client = &self.context.search;
-        let indices = IndexesQuery::new(client).with_limit(512).execute().await?;
-        let result = self.config.matrix.indices.iter();
-        for (index, _) in result {
-            let created: Vec<_> = indices.results.iter().map(|i| i.uid.clone()).collect();
-            if !created.contains(&index.to_string()) {
-                let index = client
-                    .create_index(index, Some("event_id"))
-                    .await?
-                    .wait_for_completion(client, None, None)
-                    .await?
-                    .try_make_index(client)
-                    .unwrap();
-                index
-                    .set_filterable_attributes(&["user_id", "timestamp"])
-                    .await?
-                    .wait_for_completion(client, None, None)
-                    .await?;
-                index
-                    .set_sortable_attributes(&["timestamp"])
-                    .await?
-                    .wait_for_completion(client, None, None)
-                    .await?;
+        let search = &self.context.search;
+        let mut indices: Vec<_> = self.config.matrix.indices.keys().cloned().collect();
+        indices.extend(
+            self.context
+                .store
+                .get_rooms()?
+                .into_iter()
+                .map(|info| info.index_name),
+        );
+        indices.sort();
+        indices.dedup();
+        let mut failures = 0;
+        for index in &indices {
+            if let Err(e) = search.create_index(index).await {
+                tracing::error!("Updating index {} failed: {:#}", index, e);
+                failures += 1;
             }
         }
+        if failures == indices.len() && failures > 0 {
+            bail!("Updating all indices failed");
+        }
         Ok(())
     }
 
@@ -114,13 +144,114 @@ impl LuoxuBot {
 
     pub async fn run(self) -> anyhow::Result<()> {
         self.client.add_event_handler_context(self.context.clone());
-        tracing::info!("Initial sync beginning...");
-        self.client.sync_once(SyncSettings::default()).await?;
+        self.client.add_event_handler_context(CommandSettings {
+            prefix: self.config.matrix.command_prefix.clone(),
+        });
+        // Also writes what was left in the outbox by the previous run.
+        tokio::spawn(outbox_loop(self.context.clone()));
+        // Resume from the last processed sync, so what was sent while we were offline is indexed.
+        let since = match self.context.store.get_sync_token()? {
+            Some(token) => token,
+            None => {
+                tracing::info!("Initial sync beginning...");
+                let response = self.client.sync_once(SyncSettings::default()).await?;
+                self.context
+                    .store
+                    .set_sync_token(&response.next_batch, &[])?;
+                response.next_batch
+            }
+        };
         self.client.add_event_handler(on_room_message);
+        self.client.add_event_handler(on_room_command);
+        self.client.add_event_handler(on_room_encrypted);
+        self.client.add_event_handler(on_room_member);
         self.client.add_event_handler(on_room_name);
+        self.client.add_event_handler(on_room_redaction);
         self.client.add_event_handler(on_room_tombstone);
-        let settings = SyncSettings::default().token(self.client.sync_token().await.unwrap());
-        self.client.sync(settings).await?;
+        if !self.config.matrix.spaces.is_empty() {
+            let spaces = SpaceSettings::new(self.config.matrix.spaces.clone());
+            self.client.add_event_handler_context(spaces.clone());
+            self.client.add_event_handler(on_space_child);
+            let client = self.client.clone();
+            let context = self.context.clone();
+            tokio::spawn(async move {
+                if let Err(e) = sync_spaces(client, context, spaces).await {
+                    tracing::warn!("Indexing spaces failed: {}", e);
+                }
+            });
+        }
+        if let Some(invites) = &self.config.matrix.invites {
+            self.client.add_event_handler_context(invites.clone());
+            self.client.add_event_handler(on_stripped_member);
+            let client = self.client.clone();
+            let context = self.context.clone();
+            let invites = invites.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_pending_invites(client, context, invites).await {
+                    tracing::warn!("Handling pending invites failed: {}", e);
+                }
+            });
+        }
+        if let Some(recovery_key) = &self.config.matrix.recovery_key {
+            if let Err(e) =
+                restore_backup(&self.client, self.crypto_store.as_ref(), recovery_key).await
+            {
+                tracing::warn!("Restoring key backup failed: {}", e);
+            }
+        }
+        tokio::spawn(retry_undecrypted_loop(
+            self.client.clone(),
+            self.context.clone(),
+        ));
+        if let Some(days) = self.config.matrix.delete_inactive_after_days {
+            tokio::spawn(expire_inactive_loop(
+                self.context.clone(),
+                Duration::from_secs(days * 24 * 60 * 60),
+            ));
+        }
+        if let Some(ocr) = &self.config.ocr {
+            tokio::spawn(ocr_loop(
+                self.client.clone(),
+                self.context.clone(),
+                ocr.clone(),
+            ));
+        }
+        // Catch up on members and missed redactions, and backfill the history while we keep syncing.
+        {
+            let client = self.client.clone();
+            let context = self.context.clone();
+            tokio::spawn(async move {
+                if let Err(e) = follow_upgrades(client.clone(), context.clone()).await {
+                    tracing::warn!("Following room upgrades failed: {}", e);
+                }
+                if let Err(e) = sync_members(client.clone(), context.clone()).await {
+                    tracing::warn!("Syncing members failed: {}", e);
+                }
+                if let Err(e) = reconcile_redactions(client.clone(), context.clone()).await {
+                    tracing::warn!("Reconciling redactions failed: {}", e);
+                }
+                if let Err(e) = fill_gaps(client.clone(), context.clone()).await {
+                    tracing::warn!("Backfilling gaps failed: {}", e);
+                }
+                if let Err(e) = backfill_rooms(client, context).await {
+                    tracing::warn!("Backfilling failed: {}", e);
+                }
+            });
+        }
+        let client = self.client.clone();
+        let context = self.context.clone();
+        self.client
+            .sync_with_callback(SyncSettings::default().token(since), |response| {
+                let client = client.clone();
+                let context = context.clone();
+                async move {
+                    if let Err(e) = handle_sync_response(&client, &context, &response) {
+                        tracing::warn!("Saving the sync token failed: {}", e);
+                    }
+                    LoopCtrl::Continue
+                }
+            })
+            .await?;
         Ok(())
     }
 }
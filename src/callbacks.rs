@@ -1,14 +1,17 @@
-use anyhow::Context;
 use luoxu_rs::LuoxuAvatar;
-use matrix_sdk::ruma::events::room::message::sanitize::HtmlSanitizerMode;
-use matrix_sdk::ruma::events::room::message::sanitize::RemoveReplyFallback;
+use matrix_sdk::ruma::events::room::encrypted::OriginalSyncRoomEncryptedEvent;
 use matrix_sdk::ruma::events::room::message::MessageType;
 use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
 use matrix_sdk::ruma::events::room::message::Relation;
-use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
 use matrix_sdk::ruma::events::room::name::OriginalSyncRoomNameEvent;
+use matrix_sdk::ruma::events::room::redaction::OriginalSyncRoomRedactionEvent;
 use matrix_sdk::ruma::events::room::tombstone::OriginalSyncRoomTombstoneEvent;
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::events::{
+    AnySyncMessageLikeEvent, AnySyncTimelineEvent, AnyTimelineEvent, SyncMessageLikeEvent,
+};
 use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{EventId, OwnedEventId};
 use matrix_sdk::{
     event_handler::{Ctx, RawEvent},
     room::Room,
@@ -17,38 +20,184 @@ use serde_json::Value;
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use luoxu_rs::{LuoxuBotContext, LuoxuMessage};
+use luoxu_rs::{
+    message_body, ContentKind, KeyEventId, LuoxuBotContext, LuoxuEdit, LuoxuMessage, OcrJob,
+    UndecryptedEvent,
+};
+
+use crate::commands::{Command, CommandSettings};
+use crate::upgrades::follow_upgrade;
 
 pub async fn on_room_message(
     ev: OriginalSyncRoomMessageEvent,
     room: Room,
     client: matrix_sdk::Client,
     ctx: Ctx<Arc<LuoxuBotContext>>,
+    settings: Ctx<CommandSettings>,
     raw: RawEvent,
 ) -> anyhow::Result<()> {
+    // Don't index commands sent to the bot.
+    if let MessageType::Text(content) = &ev.content.msgtype {
+        if Command::parse(&settings.prefix, &content.body).is_some() {
+            return anyhow::Ok(());
+        }
+    }
+    let value = raw.get().to_string();
+    let raw: Raw<OriginalSyncRoomMessageEvent> = Raw::from_json_string(value)?;
+    let msg = match convert_message(ev, &raw, &room, &client).await? {
+        Some(msg) => msg,
+        None => return anyhow::Ok(()),
+    };
+    // Save message.
+    if let Ok(Some(index)) = &ctx.store.get_index(room.room_id().into()) {
+        save_converted(&ctx, index, msg).await?;
+    }
+
+    anyhow::Ok(())
+}
+
+/// The result of converting a room message.
+pub enum ConvertedMessage {
+    /// A message, and the image to recognize text in.
+    Message(LuoxuMessage, Option<MediaSource>),
+    /// An edit of the message with the given event ID.
+    Edit(KeyEventId, LuoxuEdit),
+}
+
+/// Save a converted message or edit into an index.
+pub async fn save_converted(
+    ctx: &LuoxuBotContext,
+    index: &str,
+    msg: ConvertedMessage,
+) -> anyhow::Result<()> {
+    match msg {
+        ConvertedMessage::Message(msg, image) => {
+            let event_id = msg.event_id.clone();
+            save_messages(ctx, index, vec![msg])?;
+            if let Some(source) = image {
+                queue_ocr(ctx, index, &event_id, source)?;
+            }
+            Ok(())
+        }
+        ConvertedMessage::Edit(original, edit) => save_edit(ctx, index, original, edit).await,
+    }
+}
+
+/// Queue an image for OCR, if enabled.
+pub fn queue_ocr(
+    ctx: &LuoxuBotContext,
+    index: &str,
+    event_id: &KeyEventId,
+    source: MediaSource,
+) -> anyhow::Result<()> {
+    if ctx.ocr_enabled {
+        let job = OcrJob {
+            index: index.to_string(),
+            source,
+            attempts: 0,
+        };
+        ctx.store.put_ocr_job(&event_id.event_id(), &job)?;
+    }
+    Ok(())
+}
+
+/// Save messages into an index, applying edits we've seen before the originals.
+pub fn save_messages(
+    ctx: &LuoxuBotContext,
+    index: &str,
+    mut msgs: Vec<LuoxuMessage>,
+) -> anyhow::Result<()> {
+    for msg in &mut msgs {
+        let edits = ctx.store.get_edits(&msg.event_id.event_id())?;
+        msg.apply_edits(&edits);
+        ctx.normalizer.normalize_message(index, msg);
+    }
+    ctx.add_messages(index, msgs)
+}
+
+/// Record an edit, and update the original message if it has been indexed.
+///
+/// Edits of messages not indexed yet are applied once the original is saved.
+pub async fn save_edit(
+    ctx: &LuoxuBotContext,
+    index: &str,
+    original: KeyEventId,
+    edit: LuoxuEdit,
+) -> anyhow::Result<()> {
+    let edits = ctx.store.add_edit(&original.event_id(), edit)?;
+    if let Some(mut msg) = ctx.get_message(index, &original).await? {
+        msg.apply_edits(&edits);
+        ctx.normalizer.normalize_message(index, &mut msg);
+        ctx.add_messages(index, vec![msg])?;
+    }
+    Ok(())
+}
+
+/// Convert an event fetched outside of sync into a [`ConvertedMessage`].
+///
+/// Returns `None` for anything other than an unredacted room message.
+pub async fn convert_timeline_event(
+    event: Raw<AnyTimelineEvent>,
+    room: &Room,
+    client: &matrix_sdk::Client,
+) -> anyhow::Result<Option<ConvertedMessage>> {
+    let ev = match event.deserialize_as::<AnySyncTimelineEvent>() {
+        Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(ev),
+        ))) => ev,
+        _ => return Ok(None),
+    };
+    let raw = event.cast::<OriginalSyncRoomMessageEvent>();
+    convert_message(ev, &raw, room, client).await
+}
+
+/// Convert a room message into a [`ConvertedMessage`].
+///
+/// Returns `None` for messages that should not be indexed, such as our own
+/// messages or unsupported message types.
+pub async fn convert_message(
+    ev: OriginalSyncRoomMessageEvent,
+    raw: &Raw<OriginalSyncRoomMessageEvent>,
+    room: &Room,
+    client: &matrix_sdk::Client,
+) -> anyhow::Result<Option<ConvertedMessage>> {
     let user_id = ev.sender;
     // Stop processing our own messages.
     if user_id == client.user_id().unwrap() {
-        return anyhow::Ok(());
+        return Ok(None);
     }
     // Gather event infomations.
-    let value = raw.get().to_string();
-    let raw: Raw<OriginalSyncRoomMessageEvent> = Raw::from_json_string(value)?;
-    let (mut content, event_id) = match ev.content.relates_to {
-        Some(Relation::Replacement(r)) => {
-            let content: RoomMessageEventContent = *r.new_content;
-            (content, r.event_id)
-        }
-        _ => (ev.content, ev.event_id),
+    if let Some(Relation::Replacement(r)) = ev.content.relates_to {
+        let body = match message_body(*r.new_content) {
+            Some(body) => body,
+            None => return Ok(None),
+        };
+        let edit = LuoxuEdit {
+            event_id: ev.event_id.to_string(),
+            user_id,
+            body,
+            timestamp: ev.origin_server_ts,
+        };
+        return Ok(Some(ConvertedMessage::Edit(r.event_id.into(), edit)));
+    }
+    let event_id = ev.event_id;
+    let image = match &ev.content.msgtype {
+        MessageType::Image(content) => Some(content.source.clone()),
+        _ => None,
+    };
+    let mut has = match &ev.content.msgtype {
+        MessageType::Image(_) => vec![ContentKind::Image],
+        MessageType::File(_) => vec![ContentKind::File],
+        MessageType::Video(_) => vec![ContentKind::Video],
+        _ => vec![],
     };
-    content.sanitize(HtmlSanitizerMode::Strict, RemoveReplyFallback::Yes);
-    let body = match content.msgtype {
-        MessageType::Text(ev) => ev.body.trim_start().to_string(),
-        MessageType::Image(ev) => format!("[Image] {}", ev.body),
-        MessageType::File(ev) => format!("[File] {}", ev.body),
-        MessageType::Video(ev) => format!("[Video] {}", ev.body),
-        _ => return anyhow::Ok(()),
+    let body = match message_body(ev.content) {
+        Some(body) => body,
+        None => return Ok(None),
     };
+    if ContentKind::has_link(&body) {
+        has.push(ContentKind::Link);
+    }
     let external_url = {
         if let Ok(Some(content)) = raw.get_field::<HashMap<&str, _>>("content") {
             if let Some(Value::String(external_url)) = content.get("external_url") {
@@ -90,17 +239,92 @@ pub async fn on_room_message(
         timestamp,
         room_id: room_id.into(),
         ocr_body: None,
+        normalized_body: None,
+        edited_at: None,
+        has,
     };
-    // Save message.
-    let search = &ctx.search;
-    if let Ok(Some(index)) = &ctx.store.get_index(room_id.into()) {
-        search
-            .index(index)
-            .add_or_update(&[msg], None::<&str>)
-            .await?;
+    Ok(Some(ConvertedMessage::Message(msg, image)))
+}
+
+/// Only called for events the SDK failed to decrypt, keep them for a later retry.
+pub async fn on_room_encrypted(
+    ev: OriginalSyncRoomEncryptedEvent,
+    room: Room,
+    ctx: Ctx<Arc<LuoxuBotContext>>,
+    raw: RawEvent,
+) -> anyhow::Result<()> {
+    if let Ok(Some(_)) = ctx.store.get_index(room.room_id().into()) {
+        queue_undecrypted(&ctx, &room, &ev.event_id, raw.get())?;
     }
+    Ok(())
+}
 
-    anyhow::Ok(())
+/// Keep an event that failed to decrypt for a later retry.
+pub fn queue_undecrypted(
+    ctx: &LuoxuBotContext,
+    room: &Room,
+    event_id: &EventId,
+    event: &str,
+) -> anyhow::Result<()> {
+    tracing::debug!("Unable to decrypt {} in {}", event_id, room.room_id());
+    ctx.store.add_undecrypted(
+        event_id.as_str(),
+        &UndecryptedEvent {
+            room_id: room.room_id().into(),
+            event: event.to_string(),
+        },
+    )
+}
+
+pub async fn on_room_redaction(
+    ev: OriginalSyncRoomRedactionEvent,
+    room: Room,
+    client: matrix_sdk::Client,
+    ctx: Ctx<Arc<LuoxuBotContext>>,
+) -> anyhow::Result<()> {
+    if let Ok(Some(index)) = &ctx.store.get_index(room.room_id().into()) {
+        let redacted = [ev.redacts.to_string()];
+        let event_id: KeyEventId = ev.redacts.into();
+        ctx.delete_messages(index, &[event_id])?;
+        redact_edits(&client, &ctx, &room, index, &redacted).await?;
+    }
+    Ok(())
+}
+
+/// Forget redacted edits, and index their originals again without them.
+pub async fn redact_edits(
+    client: &matrix_sdk::Client,
+    ctx: &LuoxuBotContext,
+    room: &Room,
+    index: &str,
+    redacted: &[String],
+) -> anyhow::Result<()> {
+    for (edit_id, original_id) in ctx.store.get_edited_events(redacted)? {
+        let original = OwnedEventId::try_from(original_id.as_str())?;
+        let key = KeyEventId::from(original.clone());
+        let msg = match ctx.get_message(index, &key).await? {
+            Some(msg) => msg,
+            None => {
+                // Not indexed yet, the remaining edits are applied once it is.
+                ctx.store.remove_edit(&original_id, &edit_id)?;
+                continue;
+            }
+        };
+        // The indexed body may be the redacted edit, start again from the original event.
+        let event = room.event(&original).await?;
+        let converted = convert_timeline_event(event.event, room, client).await?;
+        let edits = ctx.store.remove_edit(&original_id, &edit_id)?;
+        match converted {
+            Some(ConvertedMessage::Message(mut unedited, _)) => {
+                unedited.ocr_body = msg.ocr_body;
+                unedited.apply_edits(&edits);
+                ctx.normalizer.normalize_message(index, &mut unedited);
+                ctx.add_messages(index, vec![unedited])?;
+            }
+            _ => ctx.delete_messages(index, &[key])?,
+        }
+    }
+    Ok(())
 }
 
 pub async fn on_room_name(
@@ -112,23 +336,12 @@ pub async fn on_room_name(
         .update_entry(room.room_id().as_str(), None, ev.content.name.as_deref())
 }
 
+/// Follow an indexed room to its replacement when it's upgraded.
 pub async fn on_room_tombstone(
     ev: OriginalSyncRoomTombstoneEvent,
     room: Room,
     client: matrix_sdk::Client,
     ctx: Ctx<Arc<LuoxuBotContext>>,
 ) -> anyhow::Result<()> {
-    tracing::info!(
-        "Joining new room as a room replacement happened, event: {:#?}",
-        ev
-    );
-    let _ = client
-        .join_room_by_id(&ev.content.replacement_room)
-        .await
-        .context("Joining the new room failed")?;
-    ctx.store.move_entry(
-        room.room_id().as_str(),
-        ev.content.replacement_room.as_str(),
-    )?;
-    Ok(())
+    follow_upgrade(&client, &ctx, room.room_id(), &ev.content.replacement_room).await
 }
@@ -0,0 +1,162 @@
+use luoxu_rs::search::ParsedQuery;
+use luoxu_rs::LuoxuBotContext;
+use matrix_sdk::event_handler::Ctx;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::room::message::{
+    MessageType, OriginalSyncRoomMessageEvent, Relation, RoomMessageEventContent,
+};
+use matrix_sdk::ruma::{OwnedEventId, RoomId};
+use std::sync::Arc;
+
+/// Number of hits included in a search reply.
+const SEARCH_LIMIT: usize = 5;
+
+/// Settings of the command subsystem.
+#[derive(Clone, Debug)]
+pub struct CommandSettings {
+    pub prefix: String,
+}
+
+/// A command sent to the bot.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command<'a> {
+    Search(&'a str),
+    Help,
+}
+
+impl<'a> Command<'a> {
+    /// Parse a message body, returning `None` if it isn't addressed to the bot.
+    pub fn parse(prefix: &str, body: &'a str) -> Option<Self> {
+        let rest = body.trim().strip_prefix(prefix)?;
+        if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+            return None;
+        }
+        let mut parts = rest.trim_start().splitn(2, char::is_whitespace);
+        let command = match (parts.next(), parts.next().map(str::trim)) {
+            (Some("search"), Some(query)) if !query.is_empty() => Command::Search(query),
+            _ => Command::Help,
+        };
+        Some(command)
+    }
+}
+
+pub async fn on_room_command(
+    ev: OriginalSyncRoomMessageEvent,
+    room: Room,
+    client: matrix_sdk::Client,
+    ctx: Ctx<Arc<LuoxuBotContext>>,
+    settings: Ctx<CommandSettings>,
+) -> anyhow::Result<()> {
+    let room = match room {
+        Room::Joined(room) => room,
+        _ => return Ok(()),
+    };
+    // Stop processing our own messages and edits.
+    if ev.sender == client.user_id().unwrap()
+        || matches!(ev.content.relates_to, Some(Relation::Replacement(_)))
+    {
+        return Ok(());
+    }
+    let body = match &ev.content.msgtype {
+        MessageType::Text(content) => content.body.as_str(),
+        _ => return Ok(()),
+    };
+    let command = match Command::parse(&settings.prefix, body) {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+    let index = match ctx.store.get_index(room.room_id().into())? {
+        Some(index) => index,
+        None => return Ok(()),
+    };
+
+    let content = match command {
+        Command::Search(query) => search(&ctx, &index, room.room_id(), query).await?,
+        Command::Help => RoomMessageEventContent::notice_plain(format!(
+            "Usage: {} search <query>",
+            settings.prefix
+        )),
+    };
+    let original = ev.into_full_event(room.room_id().into());
+    room.send(content.make_reply_to(&original), None).await?;
+    Ok(())
+}
+
+async fn search(
+    ctx: &LuoxuBotContext,
+    index: &str,
+    room_id: &RoomId,
+    query: &str,
+) -> anyhow::Result<RoomMessageEventContent> {
+    let parsed = match ParsedQuery::parse(query) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return Ok(RoomMessageEventContent::notice_plain(format!(
+                "Invalid query: {}",
+                e
+            )))
+        }
+    };
+    let mut search_query = parsed.search_query(&ctx.normalizer, index);
+    search_query.limit = SEARCH_LIMIT;
+    // Only search this room and the rooms it replaced when other rooms share the index.
+    if ctx.store.is_shared_index(index)? {
+        let predecessors = ctx
+            .store
+            .get_rooms()?
+            .into_iter()
+            .find(|info| info.room_id == room_id.as_str())
+            .map(|info| info.predecessors)
+            .unwrap_or_default();
+        for predecessor in predecessors {
+            search_query.filter.rooms.push(predecessor.try_into()?);
+        }
+        search_query.filter.rooms.push(room_id.to_owned());
+    }
+    let hits = match parsed.allows_index(index) {
+        true => ctx.search.search(index, &search_query).await?.hits,
+        false => vec![],
+    };
+    if hits.is_empty() {
+        return Ok(RoomMessageEventContent::notice_plain(format!(
+            "No results for \"{}\".",
+            query
+        )));
+    }
+
+    let mut plain = format!("Results for \"{}\":\n", query);
+    let mut html = format!("<p>Results for \"{}\":</p><ol>", escape_html(query));
+    for hit in hits {
+        let msg = hit.message;
+        let event_id = OwnedEventId::try_from(msg.event_id.event_id())?;
+        let permalink = msg.room_id.matrix_to_event_uri(event_id);
+        let sender = msg
+            .user_display_name
+            .as_deref()
+            .unwrap_or(msg.user_id.as_str());
+        plain.push_str(&format!("- {}: {} ({})\n", sender, msg.body, permalink));
+        html.push_str(&format!(
+            "<li><a href=\"{}\">{}</a>: {}</li>",
+            permalink,
+            escape_html(sender),
+            escape_html(&msg.body)
+        ));
+    }
+    html.push_str("</ol>");
+    Ok(RoomMessageEventContent::notice_html(plain, html))
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,219 @@
+use anyhow::{bail, Context};
+use luoxu_rs::LuoxuBotContext;
+use matrix_sdk::ruma::api::client::backup::{
+    get_backup_keys, get_latest_backup_info, BackupAlgorithm, SessionData,
+};
+use matrix_sdk::ruma::events::room::encrypted::OriginalSyncRoomEncryptedEvent;
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk_crypto::olm::{ExportedRoomKey, InboundGroupSession};
+use matrix_sdk_crypto::store::{Changes, CryptoStore, RecoveryKey};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::callbacks::{convert_timeline_event, save_converted};
+
+/// How often events that failed to decrypt are retried.
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Decode a base58 recovery key as displayed by Element.
+fn decode_recovery_key(recovery_key: &str) -> anyhow::Result<RecoveryKey> {
+    RecoveryKey::from_base58(recovery_key).context("Invalid recovery key")
+}
+
+/// Decrypt a backed up room key using `m.megolm_backup.v1.curve25519-aes-sha2`.
+fn decrypt_session_data(
+    recovery_key: &RecoveryKey,
+    data: &SessionData,
+) -> anyhow::Result<serde_json::Map<String, Value>> {
+    // `decrypt_v1` hands its first two arguments to olm in the opposite order than it names
+    // them, so the ephemeral key goes first.
+    let plaintext = recovery_key.decrypt_v1(
+        data.ephemeral.encode(),
+        data.mac.encode(),
+        data.ciphertext.encode(),
+    )?;
+    Ok(serde_json::from_str(&plaintext)?)
+}
+
+/// Import all room keys from the latest server-side key backup.
+///
+/// The keys are saved straight into the crypto store of the client, keeping the sessions
+/// it already knows from an earlier message index.
+pub async fn restore_backup(
+    client: &matrix_sdk::Client,
+    crypto_store: &dyn CryptoStore,
+    recovery_key: &str,
+) -> anyhow::Result<()> {
+    let recovery_key = decode_recovery_key(recovery_key)?;
+    let info = client
+        .send(get_latest_backup_info::v3::Request::new(), None)
+        .await
+        .context("Fetching key backup info failed")?;
+    match info.algorithm.deserialize()? {
+        BackupAlgorithm::MegolmBackupV1Curve25519AesSha2 { public_key, .. } => {
+            if recovery_key.megolm_v1_public_key().to_base64() != public_key.encode() {
+                bail!("Recovery key does not match key backup {}", info.version);
+            }
+        }
+        _ => bail!("Unsupported key backup algorithm"),
+    }
+    tracing::info!("Restoring room keys from key backup {}", info.version);
+
+    let response = client
+        .send(get_backup_keys::v3::Request::new(&info.version), None)
+        .await
+        .context("Fetching backed up keys failed")?;
+    let mut total = 0;
+    let mut sessions = Vec::new();
+    for (room_id, backup) in response.rooms {
+        for (session_id, data) in backup.sessions {
+            total += 1;
+            let data = data.deserialize()?;
+            let mut key = match decrypt_session_data(&recovery_key, &data.session_data) {
+                Ok(key) => key,
+                Err(e) => {
+                    tracing::warn!("Skipping backed up session {}: {}", session_id, e);
+                    continue;
+                }
+            };
+            key.insert("room_id".to_string(), room_id.to_string().into());
+            key.insert("session_id".to_string(), session_id.clone().into());
+            let key: ExportedRoomKey = serde_json::from_value(Value::Object(key))?;
+            let session = match InboundGroupSession::from_export(&key) {
+                Ok(session) => session,
+                Err(e) => {
+                    tracing::warn!("Skipping backed up session {}: {}", session_id, e);
+                    continue;
+                }
+            };
+            let known = crypto_store
+                .get_inbound_group_session(
+                    session.room_id(),
+                    &session.sender_key().to_base64(),
+                    session.session_id(),
+                )
+                .await?;
+            // A session known from an earlier index decrypts more messages.
+            if known.is_none_or(|known| session.first_known_index() < known.first_known_index()) {
+                session.mark_as_backed_up();
+                sessions.push(session);
+            }
+        }
+    }
+    let imported = sessions.len();
+    crypto_store
+        .save_changes(Changes {
+            inbound_group_sessions: sessions,
+            ..Default::default()
+        })
+        .await?;
+    tracing::info!("Imported {} room keys out of {}", imported, total);
+    Ok(())
+}
+
+/// Index the events that previously failed to decrypt, if the keys arrived since.
+pub async fn retry_undecrypted(
+    client: &matrix_sdk::Client,
+    ctx: &LuoxuBotContext,
+) -> anyhow::Result<()> {
+    for (event_id, pending) in ctx.store.get_undecrypted()? {
+        let room = match client.get_room(&pending.room_id) {
+            Some(room) => room,
+            None => {
+                ctx.store.remove_undecrypted(&event_id)?;
+                continue;
+            }
+        };
+        let raw: Raw<OriginalSyncRoomEncryptedEvent> = Raw::from_json_string(pending.event)?;
+        let event = match room.decrypt_event(&raw).await {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+        if let Some(msg) = convert_timeline_event(event.event, &room, client).await? {
+            if let Ok(Some(index)) = ctx.store.get_index(pending.room_id) {
+                save_converted(ctx, &index, msg).await?;
+            }
+        }
+        ctx.store.remove_undecrypted(&event_id)?;
+    }
+    Ok(())
+}
+
+/// Periodically retry decrypting events whose room keys were missing.
+pub async fn retry_undecrypted_loop(client: matrix_sdk::Client, ctx: Arc<LuoxuBotContext>) {
+    let mut interval = tokio::time::interval(RETRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = retry_undecrypted(&client, &ctx).await {
+            tracing::warn!("Retrying undecrypted events failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::api::client::backup::SessionDataInit;
+    use matrix_sdk::ruma::serde::Base64;
+
+    /// The recovery key of the bytes 0 to 31.
+    const RECOVERY_KEY: &str = "EsSzykH7LCZx7CaecmKDwcmYJRXiYbtu8iQ3t8EznRwKpUY1";
+
+    fn key_bytes() -> [u8; 32] {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    #[test]
+    fn recovery_key_known_answer() {
+        let key = decode_recovery_key(RECOVERY_KEY).unwrap();
+        assert_eq!(key.as_bytes(), &key_bytes());
+        assert_eq!(
+            RecoveryKey::from_bytes(&key_bytes()).to_base58(),
+            RECOVERY_KEY
+        );
+    }
+
+    #[test]
+    fn recovery_key_ignores_whitespace() {
+        let spaced: Vec<_> = RECOVERY_KEY
+            .as_bytes()
+            .chunks(4)
+            .map(|chunk| std::str::from_utf8(chunk).unwrap())
+            .collect();
+        let key = decode_recovery_key(&format!(" {}\n", spaced.join(" "))).unwrap();
+        assert_eq!(key.as_bytes(), &key_bytes());
+    }
+
+    #[test]
+    fn recovery_key_checks_prefix_and_parity() {
+        // A valid parity byte after the prefix 0x8b 0x02.
+        assert!(decode_recovery_key("EsUK2XMzQ91XMHMNdsnA6YDRpvsEX2ddqzUFhASF8FFp2KYc").is_err());
+        // The key bytes with a wrong parity byte.
+        assert!(decode_recovery_key("EsSzykH7LCZx7CaecmKDwcmYJRXiYbtu8iQ3t8EznRwKpUXe").is_err());
+        // Too short, and not base58.
+        assert!(decode_recovery_key(&RECOVERY_KEY[..40]).is_err());
+        assert!(decode_recovery_key("EsSzykH7LCZx7Cae0OIl").is_err());
+    }
+
+    fn session_data(message: olm_rs::pk::PkMessage) -> SessionData {
+        SessionDataInit {
+            ephemeral: Base64::parse(message.ephemeral_key).unwrap(),
+            ciphertext: Base64::parse(message.ciphertext).unwrap(),
+            mac: Base64::parse(message.mac).unwrap(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn decrypt_session_data_round_trip() {
+        let key = RecoveryKey::from_bytes(&key_bytes());
+        let encryption = olm_rs::pk::OlmPkEncryption::new(&key.megolm_v1_public_key().to_base64());
+        let data = session_data(encryption.encrypt(r#"{"session_key":"abc"}"#));
+        let decrypted = decrypt_session_data(&key, &data).unwrap();
+        assert_eq!(decrypted["session_key"], "abc");
+
+        let other = RecoveryKey::from_bytes(&[1; 32]);
+        assert!(decrypt_session_data(&other, &data).is_err());
+    }
+}
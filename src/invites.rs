@@ -0,0 +1,135 @@
+use luoxu_rs::{LuoxuBotContext, LuoxuConfigInvites};
+use matrix_sdk::event_handler::Ctx;
+use matrix_sdk::room::{Invited, Room};
+use matrix_sdk::ruma::events::room::member::StrippedRoomMemberEvent;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::{RoomId, UserId};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::backfill::backfill_room;
+use crate::members::sync_room_members;
+
+/// Number of attempts to join a room, as the homeserver may not be ready right after the invite.
+const JOIN_ATTEMPTS: u32 = 5;
+
+/// Accept invites of allowed users, and start indexing the joined rooms.
+pub async fn on_stripped_member(
+    ev: StrippedRoomMemberEvent,
+    room: Room,
+    client: matrix_sdk::Client,
+    ctx: Ctx<Arc<LuoxuBotContext>>,
+    settings: Ctx<LuoxuConfigInvites>,
+) {
+    if Some(ev.state_key.as_ref()) != client.user_id() {
+        return;
+    }
+    if let Room::Invited(room) = room {
+        // Joining waits for the sync loop, so it can't happen in the handler.
+        tokio::spawn(handle_invite(client, ctx.0, settings.0, room, ev.sender));
+    }
+}
+
+/// Handle the invites received while the bot was offline.
+pub async fn handle_pending_invites(
+    client: matrix_sdk::Client,
+    ctx: Arc<LuoxuBotContext>,
+    settings: LuoxuConfigInvites,
+) -> anyhow::Result<()> {
+    for room in client.invited_rooms() {
+        let inviter = match room.invite_details().await?.inviter {
+            Some(inviter) => inviter.user_id().to_owned(),
+            None => continue,
+        };
+        handle_invite(client.clone(), ctx.clone(), settings.clone(), room, inviter).await;
+    }
+    Ok(())
+}
+
+async fn handle_invite(
+    client: matrix_sdk::Client,
+    ctx: Arc<LuoxuBotContext>,
+    settings: LuoxuConfigInvites,
+    room: Invited,
+    inviter: impl AsRef<UserId>,
+) {
+    let room_id = room.room_id().to_owned();
+    let inviter = inviter.as_ref();
+    if !settings.allows(inviter) {
+        tracing::info!("Rejecting invite to {} from {}", room_id, inviter);
+        if let Err(e) = room.reject_invitation().await {
+            tracing::warn!("Rejecting invite to {} failed: {}", room_id, e);
+        }
+        return;
+    }
+    tracing::info!("Joining {} as invited by {}", room_id, inviter);
+    if let Err(e) = join_and_index(&client, &ctx, &settings, &room).await {
+        tracing::warn!("Indexing {} failed: {:#}", room_id, e);
+    }
+}
+
+async fn join_and_index(
+    client: &matrix_sdk::Client,
+    ctx: &LuoxuBotContext,
+    settings: &LuoxuConfigInvites,
+    room: &Invited,
+) -> anyhow::Result<()> {
+    let room_id = room.room_id();
+    let mut delay = Duration::from_secs(2);
+    for attempt in 1..=JOIN_ATTEMPTS {
+        match room.accept_invitation().await {
+            Ok(()) => break,
+            Err(e) if attempt == JOIN_ATTEMPTS => return Err(e.into()),
+            Err(e) => {
+                tracing::warn!("Joining {} failed, retrying in {:?}: {}", room_id, delay, e);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+    }
+
+    let index = match ctx.store.get_index(room_id.to_owned())? {
+        Some(index) => index,
+        None => settings.index_name(room_id.as_str()),
+    };
+    index_joined_room(client, ctx, room_id, &index, Some(&settings.notice)).await
+}
+
+/// Index a room the bot just joined, optionally posting a notice, and backfill its history.
+pub async fn index_joined_room(
+    client: &matrix_sdk::Client,
+    ctx: &LuoxuBotContext,
+    room_id: &RoomId,
+    index: &str,
+    notice: Option<&str>,
+) -> anyhow::Result<()> {
+    if ctx.store.get_index(room_id.to_owned())?.is_none() {
+        ctx.search.create_index(index).await?;
+        ctx.store.add_entry(room_id.as_str(), index, None)?;
+    }
+    tracing::info!("Indexing {} in {}", room_id, index);
+
+    // The room is only known as joined once the sync loop saw the join.
+    let mut joined = None;
+    for _ in 0..30 {
+        joined = client.get_joined_room(room_id);
+        if joined.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    let room = match joined {
+        Some(room) => room,
+        None => anyhow::bail!("{} not joined after joining it", room_id),
+    };
+    if let Some(name) = room.name() {
+        ctx.store
+            .update_entry(room_id.as_str(), None, Some(&name))?;
+    }
+    sync_room_members(ctx, &room).await?;
+    if let Some(notice) = notice {
+        room.send(RoomMessageEventContent::notice_plain(notice), None)
+            .await?;
+    }
+    backfill_room(client, ctx, &Room::Joined(room), index).await
+}
@@ -1,26 +1,100 @@
 use anyhow::Result;
-use heed::types::Str;
+use heed::types::{SerdeJson, Str};
 use heed::EnvOpenOptions;
 use matrix_sdk::reqwest::Url;
+use matrix_sdk::ruma::events::room::message::sanitize::{HtmlSanitizerMode, RemoveReplyFallback};
+use matrix_sdk::ruma::events::room::message::{MessageType, RoomMessageEventContent};
+use matrix_sdk::ruma::events::room::MediaSource;
 use matrix_sdk::ruma::MilliSecondsSinceUnixEpoch;
-use matrix_sdk::ruma::{MxcUri, OwnedEventId, OwnedRoomId, OwnedUserId};
+use matrix_sdk::ruma::{MxcUri, OwnedEventId, OwnedRoomId, OwnedUserId, UserId};
 use serde::Deserialize;
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::normalize::Normalizer;
+use crate::search::{
+    IndexSettings, MeilisearchBackend, SearchBackend, SharedIndexBackend, SqliteBackend,
+    TantivyBackend,
+};
+
+pub mod normalize;
+pub mod search;
 
 static CONFIG_FILE: &str = "luoxu-rs.toml";
+static SESSION_JSON_FILE: &str = "credentials.json";
+
+/// Get the saved login session of the bot.
+pub fn get_session() -> Result<matrix_sdk::Session> {
+    use std::fs;
+    Ok(serde_json::from_str(&fs::read_to_string(
+        SESSION_JSON_FILE,
+    )?)?)
+}
+
+/// Save the login session of the bot.
+pub fn save_session(session: &matrix_sdk::Session) -> Result<()> {
+    use std::fs;
+    fs::write(SESSION_JSON_FILE, serde_json::to_string(session)?)?;
+    Ok(())
+}
 
 #[derive(Clone)]
 pub struct LuoxuBotContext {
-    pub search: meilisearch_sdk::client::Client,
+    pub search: Arc<dyn SearchBackend>,
     pub store: HeedStore,
+    pub normalizer: Normalizer,
+    /// Whether images are queued for OCR.
+    pub ocr_enabled: bool,
+    /// Batches the changes put in the outbox.
+    pub outbox: Arc<OutboxBatcher>,
+}
+
+impl LuoxuBotContext {
+    /// Queue messages to be added to an index, replacing the ones with the same event ID.
+    pub fn add_messages(&self, index: &str, msgs: Vec<LuoxuMessage>) -> Result<()> {
+        let entries: Vec<_> = msgs
+            .into_iter()
+            .map(|msg| OutboxEntry::AddOrUpdate(Box::new(msg)))
+            .collect();
+        self.store.put_outbox(index, &entries)?;
+        self.outbox.queued(entries.len());
+        Ok(())
+    }
+
+    /// Queue messages to be deleted from an index.
+    pub fn delete_messages(&self, index: &str, event_ids: &[KeyEventId]) -> Result<()> {
+        let entries: Vec<_> = event_ids.iter().cloned().map(OutboxEntry::Delete).collect();
+        self.store.put_outbox(index, &entries)?;
+        self.outbox.queued(entries.len());
+        Ok(())
+    }
+
+    /// Get a message, including changes still waiting in the outbox.
+    pub async fn get_message(
+        &self,
+        index: &str,
+        event_id: &KeyEventId,
+    ) -> Result<Option<LuoxuMessage>> {
+        match self.store.get_outbox(index, event_id)? {
+            Some(OutboxEntry::AddOrUpdate(msg)) => Ok(Some(*msg)),
+            Some(OutboxEntry::Delete(_)) => Ok(None),
+            None => self.search.get(index, event_id).await,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
 #[allow(dead_code)]
 pub struct LuoxuConfig {
     pub matrix: LuoxuConfigMatrix,
-    pub meilisearch: LuoxuConfigMeilisearch,
+    #[serde(default)]
+    pub search: LuoxuConfigSearch,
+    pub meilisearch: Option<LuoxuConfigMeilisearch>,
+    pub ocr: Option<LuoxuConfigOcr>,
     pub state: LuoxuConfigState,
 }
 
@@ -41,15 +115,111 @@ impl LuoxuConfig {
         let _ = fs::create_dir_all(&config.state.location);
 
         let store = HeedStore::new(&config.state.location)?;
+        let mut search = config.get_search_backend()?;
+        if let Some(index) = &config.search.shared_index {
+            search = Arc::new(SharedIndexBackend::new(search, index, store.clone()));
+        }
         let context = LuoxuBotContext {
-            search: meilisearch_sdk::client::Client::new(
-                &config.meilisearch.url,
-                Some(&config.meilisearch.key),
-            ),
+            search,
             store,
+            normalizer: Normalizer::new(config.search.chinese_variants.clone()),
+            ocr_enabled: config.ocr.is_some(),
+            outbox: Arc::new(OutboxBatcher::new(
+                config.search.batch_size,
+                Duration::from_millis(config.search.batch_window_ms),
+            )),
         };
         Ok(context)
     }
+
+    pub fn get_search_backend(&self) -> anyhow::Result<Arc<dyn SearchBackend>> {
+        let backend: Arc<dyn SearchBackend> = match self.search.backend {
+            SearchBackendKind::Meilisearch => match &self.meilisearch {
+                Some(meilisearch) => Arc::new(
+                    MeilisearchBackend::new(&meilisearch.url, &meilisearch.key)
+                        .with_settings(meilisearch.settings.clone(), meilisearch.indices.clone()),
+                ),
+                None => anyhow::bail!("The Meilisearch backend requires a [meilisearch] section"),
+            },
+            SearchBackendKind::Tantivy => Arc::new(TantivyBackend::new(&self.search.path)?),
+            SearchBackendKind::Sqlite => Arc::new(SqliteBackend::new(&self.search.path)?),
+        };
+        Ok(backend)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct LuoxuConfigSearch {
+    #[serde(default)]
+    pub backend: SearchBackendKind,
+    /// The directory of embedded backends.
+    #[serde(default = "default_search_path")]
+    pub path: String,
+    /// The Chinese variant messages of an index are converted to before searching.
+    #[serde(default)]
+    pub chinese_variants: HashMap<String, zhconv::Variant>,
+    /// The maximum number of changes written to the backend at once.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// How long changes are buffered before being written, in milliseconds.
+    #[serde(default = "default_batch_window_ms")]
+    pub batch_window_ms: u64,
+    /// Keep the messages of every group in this single index, filtered by room.
+    #[serde(default)]
+    pub shared_index: Option<String>,
+}
+
+impl Default for LuoxuConfigSearch {
+    fn default() -> Self {
+        LuoxuConfigSearch {
+            backend: SearchBackendKind::default(),
+            path: default_search_path(),
+            chinese_variants: HashMap::new(),
+            batch_size: default_batch_size(),
+            batch_window_ms: default_batch_window_ms(),
+            shared_index: None,
+        }
+    }
+}
+
+fn default_search_path() -> String {
+    "indices".to_string()
+}
+
+fn default_batch_size() -> usize {
+    1000
+}
+
+fn default_batch_window_ms() -> u64 {
+    1000
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LuoxuConfigOcr {
+    /// The Tesseract executable.
+    #[serde(default = "default_ocr_command")]
+    pub command: String,
+    /// Tesseract languages, joined with `+`.
+    #[serde(default = "default_ocr_languages")]
+    pub languages: String,
+}
+
+fn default_ocr_command() -> String {
+    "tesseract".to_string()
+}
+
+fn default_ocr_languages() -> String {
+    "chi_sim+eng".to_string()
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchBackendKind {
+    #[default]
+    Meilisearch,
+    Tantivy,
+    Sqlite,
 }
 
 #[derive(Deserialize, Debug)]
@@ -60,6 +230,93 @@ pub struct LuoxuConfigMatrix {
     pub password: Option<String>,
     pub device_name: String,
     pub indices: HashMap<String, String>,
+    pub store_passphrase: Option<String>,
+    pub recovery_key: Option<String>,
+    #[serde(default = "default_command_prefix")]
+    pub command_prefix: String,
+    /// Join and index rooms the bot is invited to.
+    pub invites: Option<LuoxuConfigInvites>,
+    /// Delete the messages of rooms the bot left or was removed from after this many days.
+    pub delete_inactive_after_days: Option<u64>,
+    /// Spaces whose rooms are joined and indexed, by name.
+    #[serde(default)]
+    pub spaces: HashMap<String, LuoxuConfigSpace>,
+}
+
+fn default_command_prefix() -> String {
+    "!luoxu".to_string()
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LuoxuConfigInvites {
+    /// Users (`@alice:example.org`) and servers (`example.org`) whose invites are accepted.
+    pub allow: Vec<String>,
+    /// The index of a joined room, `{localpart}` and `{server}` are replaced by the parts of its room ID.
+    #[serde(default = "default_index_name")]
+    pub index_name: String,
+    /// Posted in a room after joining it.
+    #[serde(default = "default_invite_notice")]
+    pub notice: String,
+}
+
+impl LuoxuConfigInvites {
+    /// Whether invites sent by a user are accepted.
+    pub fn allows(&self, user_id: &UserId) -> bool {
+        self.allow
+            .iter()
+            .any(|allowed| match allowed.starts_with('@') {
+                true => allowed == user_id.as_str(),
+                false => allowed == user_id.server_name().as_str(),
+            })
+    }
+
+    /// Get the index name of a room from the template.
+    pub fn index_name(&self, room_id: &str) -> String {
+        index_name_from_template(&self.index_name, room_id)
+    }
+}
+
+/// Space whose rooms are all joined and indexed.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LuoxuConfigSpace {
+    /// The room ID or alias of the space.
+    pub space: String,
+    /// Index every room in one index named like this space entry, instead of one index per room.
+    #[serde(default)]
+    pub shared: bool,
+    /// The index of each room when not shared, like [`LuoxuConfigInvites::index_name`].
+    #[serde(default = "default_index_name")]
+    pub index_name: String,
+}
+
+/// Get the index name of a room from a template, where `{localpart}` and `{server}` are replaced
+/// by the parts of its room ID, keeping only characters allowed in index names.
+pub fn index_name_from_template(template: &str, room_id: &str) -> String {
+    let (localpart, server) = room_id
+        .trim_start_matches('!')
+        .split_once(':')
+        .unwrap_or((room_id, ""));
+    template
+        .replace("{localpart}", localpart)
+        .replace("{server}", server)
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect()
+}
+
+fn default_index_name() -> String {
+    "room_{localpart}".to_string()
+}
+
+fn default_invite_notice() -> String {
+    "This room is now indexed for search. Its members can search its messages, \
+     including messages sent before the bot joined."
+        .to_string()
 }
 
 #[derive(Deserialize, Debug)]
@@ -67,6 +324,12 @@ pub struct LuoxuConfigMatrix {
 pub struct LuoxuConfigMeilisearch {
     pub url: String,
     pub key: String,
+    /// Settings of every index.
+    #[serde(default)]
+    pub settings: IndexSettings,
+    /// Settings of specific indices, overriding `settings`.
+    #[serde(default)]
+    pub indices: HashMap<String, IndexSettings>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -87,6 +350,113 @@ pub struct LuoxuMessage {
     pub timestamp: MilliSecondsSinceUnixEpoch,
     pub room_id: OwnedRoomId,
     pub ocr_body: Option<String>,
+    #[serde(default)]
+    pub edited_at: Option<MilliSecondsSinceUnixEpoch>,
+    /// The body converted to the Chinese variant of the index, if it differs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normalized_body: Option<String>,
+    /// Kinds of content in the message.
+    #[serde(default)]
+    pub has: Vec<ContentKind>,
+}
+
+/// A kind of content a message can have, matched by `has:` in queries.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentKind {
+    Image,
+    File,
+    Video,
+    Link,
+}
+
+impl ContentKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentKind::Image => "image",
+            ContentKind::File => "file",
+            ContentKind::Video => "video",
+            ContentKind::Link => "link",
+        }
+    }
+
+    /// Whether a body contains a link.
+    pub fn has_link(body: &str) -> bool {
+        body.contains("https://") || body.contains("http://")
+    }
+}
+
+impl std::str::FromStr for ContentKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "image" => Ok(ContentKind::Image),
+            "file" => Ok(ContentKind::File),
+            "video" => Ok(ContentKind::Video),
+            "link" => Ok(ContentKind::Link),
+            _ => anyhow::bail!("Unknown content kind: {}", s),
+        }
+    }
+}
+
+/// Get the text to index of a message, or `None` for unsupported message types.
+pub fn message_body(mut content: RoomMessageEventContent) -> Option<String> {
+    content.sanitize(HtmlSanitizerMode::Strict, RemoveReplyFallback::Yes);
+    let body = match content.msgtype {
+        MessageType::Text(ev) => ev.body.trim_start().to_string(),
+        MessageType::Image(ev) => format!("[Image] {}", ev.body),
+        MessageType::File(ev) => format!("[File] {}", ev.body),
+        MessageType::Video(ev) => format!("[Video] {}", ev.body),
+        _ => return None,
+    };
+    Some(body)
+}
+
+impl LuoxuMessage {
+    /// The body matched against queries.
+    pub fn search_body(&self) -> &str {
+        self.normalized_body.as_deref().unwrap_or(&self.body)
+    }
+
+    /// The text matched against queries, with the OCR text of images on a new line.
+    pub fn search_text(&self) -> String {
+        match &self.ocr_body {
+            Some(ocr_body) => format!("{}\n{}", self.search_body(), ocr_body),
+            None => self.search_body().to_string(),
+        }
+    }
+
+    /// Replace the body with the latest edit sent by the original sender.
+    pub fn apply_edits(&mut self, edits: &[LuoxuEdit]) {
+        let latest = edits
+            .iter()
+            .filter(|edit| edit.user_id == self.user_id)
+            .max_by_key(|edit| edit.timestamp);
+        if let Some(edit) = latest {
+            self.body = edit.body.clone();
+            self.edited_at = Some(edit.timestamp);
+            self.refresh_link();
+        }
+    }
+
+    /// Update the link kind of content after the body changed.
+    pub fn refresh_link(&mut self) {
+        self.has.retain(|kind| *kind != ContentKind::Link);
+        if ContentKind::has_link(&self.body) {
+            self.has.push(ContentKind::Link);
+        }
+    }
+}
+
+/// A single edit of a message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LuoxuEdit {
+    /// The event ID of the edit event.
+    pub event_id: String,
+    pub user_id: OwnedUserId,
+    pub body: String,
+    pub timestamp: MilliSecondsSinceUnixEpoch,
 }
 
 /// A wrapper for a avatar.
@@ -120,6 +490,10 @@ impl KeyEventId {
     pub fn event_id(&self) -> String {
         format!("${}", self.0)
     }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
 }
 
 #[derive(Clone)]
@@ -128,25 +502,199 @@ pub struct HeedStore {
     pub env: heed::Env,
     pub index_db: heed::Database<Str, Str>,
     pub name_db: heed::Database<Str, Str>,
+    pub backfill_db: heed::Database<Str, SerdeJson<BackfillState>>,
+    pub redaction_db: heed::Database<Str, Str>,
+    pub undecrypted_db: heed::Database<Str, SerdeJson<UndecryptedEvent>>,
+    pub edit_db: heed::Database<Str, SerdeJson<Vec<LuoxuEdit>>>,
+    pub ocr_db: heed::Database<Str, SerdeJson<OcrJob>>,
+    /// Joined members of indexed rooms, used to authorize web users.
+    pub member_db: heed::Database<Str, SerdeJson<Vec<OwnedUserId>>>,
+    /// Changes waiting to be written to the search backend, by index and event ID.
+    pub outbox_db: heed::Database<Str, SerdeJson<OutboxEntry>>,
+    /// The token of the last processed sync response.
+    pub sync_db: heed::Database<Str, Str>,
+    /// Gaps of limited sync timelines waiting to be backfilled, by room ID and sync token.
+    pub gap_db: heed::Database<Str, SerdeJson<SyncGap>>,
+    /// Indexed rooms the bot is no longer in.
+    pub inactive_db: heed::Database<Str, SerdeJson<InactiveRoom>>,
+    /// The room that replaced an upgraded room, by the ID of the upgraded room.
+    pub upgrade_db: heed::Database<Str, Str>,
+    /// Changes of the outbox the search backend kept rejecting, by outbox key.
+    pub failed_outbox_db: heed::Database<Str, SerdeJson<OutboxEntry>>,
+}
+
+/// Events of a room skipped by a limited sync timeline.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct SyncGap {
+    /// Paginate backwards from this `/messages` token.
+    pub from: String,
+    /// The sync token the previous response ended at.
+    pub to: String,
+}
+
+fn gap_key(room_id: &str, gap: &SyncGap) -> String {
+    format!("{} {}", room_id, gap.to)
+}
+
+/// Wakes the outbox flusher once a batch of changes is full or has waited long enough.
+pub struct OutboxBatcher {
+    /// Number of changes queued since the last batch.
+    queued: AtomicUsize,
+    notify: Notify,
+    pub batch_size: usize,
+    pub batch_window: Duration,
+}
+
+impl OutboxBatcher {
+    pub fn new(batch_size: usize, batch_window: Duration) -> Self {
+        OutboxBatcher {
+            queued: AtomicUsize::new(0),
+            notify: Notify::new(),
+            batch_size: batch_size.max(1),
+            batch_window,
+        }
+    }
+
+    /// Record changes put in the outbox.
+    pub fn queued(&self, count: usize) {
+        self.queued.fetch_add(count, Ordering::SeqCst);
+        self.notify.notify_one();
+    }
+
+    /// Wait until a batch is full, or the batch window since the first queued change elapsed.
+    pub async fn wait_batch(&self) {
+        if self.queued.load(Ordering::SeqCst) == 0 {
+            self.notify.notified().await;
+        }
+        let deadline = tokio::time::Instant::now() + self.batch_window;
+        while self.queued.load(Ordering::SeqCst) < self.batch_size {
+            if tokio::time::timeout_at(deadline, self.notify.notified())
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        self.queued.store(0, Ordering::SeqCst);
+    }
+}
+
+/// A change of an index waiting in the outbox.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum OutboxEntry {
+    AddOrUpdate(Box<LuoxuMessage>),
+    Delete(KeyEventId),
+}
+
+impl OutboxEntry {
+    pub fn event_id(&self) -> &KeyEventId {
+        match self {
+            OutboxEntry::AddOrUpdate(msg) => &msg.event_id,
+            OutboxEntry::Delete(event_id) => event_id,
+        }
+    }
+}
+
+fn outbox_key(index: &str, event_id: &KeyEventId) -> String {
+    format!("{} {}", index, event_id.as_str())
+}
+
+/// An image waiting for its text to be recognized.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OcrJob {
+    pub index: String,
+    pub source: MediaSource,
+    /// Number of failed attempts so far.
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+/// An encrypted event we didn't have the room key for yet.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct UndecryptedEvent {
+    pub room_id: OwnedRoomId,
+    /// The raw JSON of the encrypted event.
+    pub event: String,
+}
+
+/// Progress of backfilling a room's history into its index.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum BackfillState {
+    /// Paginating backwards, resume from this `/messages` token.
+    Paginating(String),
+    /// Reached the start of the visible room history.
+    Completed,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct RoomInfo {
+    pub room_id: String,
     pub index_name: String,
     pub room_name: Option<String>,
+    /// Set when the bot is no longer in the room, so its index isn't updated anymore.
+    pub inactive: Option<InactiveRoom>,
+    /// The rooms this room replaced, oldest first.
+    pub predecessors: Vec<String>,
+    /// The room that replaced this room.
+    pub successor: Option<String>,
+}
+
+/// Why and since when the bot is no longer in an indexed room.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct InactiveRoom {
+    pub reason: InactiveReason,
+    /// The reason given by the user who removed the bot.
+    pub message: Option<String>,
+    pub since: MilliSecondsSinceUnixEpoch,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InactiveReason {
+    Left,
+    Kicked,
+    Banned,
 }
 
 impl HeedStore {
     pub fn new(location: &str) -> Result<Self> {
-        let env = EnvOpenOptions::new().max_dbs(2).open(location)?;
+        let env = EnvOpenOptions::new()
+            .max_dbs(14)
+            // Leave room for the outbox to grow while the search backend is down.
+            .map_size(1 << 30)
+            .open(location)?;
         let mut wtxn = env.write_txn()?;
         let index_db = env.create_database(&mut wtxn, Some("index"))?;
         let name_db = env.create_database(&mut wtxn, Some("name"))?;
+        let backfill_db = env.create_database(&mut wtxn, Some("backfill"))?;
+        let redaction_db = env.create_database(&mut wtxn, Some("redaction"))?;
+        let undecrypted_db = env.create_database(&mut wtxn, Some("undecrypted"))?;
+        let edit_db = env.create_database(&mut wtxn, Some("edit"))?;
+        let ocr_db = env.create_database(&mut wtxn, Some("ocr"))?;
+        let member_db = env.create_database(&mut wtxn, Some("member"))?;
+        let outbox_db = env.create_database(&mut wtxn, Some("outbox"))?;
+        let sync_db = env.create_database(&mut wtxn, Some("sync"))?;
+        let gap_db = env.create_database(&mut wtxn, Some("gap"))?;
+        let inactive_db = env.create_database(&mut wtxn, Some("inactive"))?;
+        let upgrade_db = env.create_database(&mut wtxn, Some("upgrade"))?;
+        let failed_outbox_db = env.create_database(&mut wtxn, Some("failed_outbox"))?;
         wtxn.commit()?;
         Ok(HeedStore {
             env,
             index_db,
             name_db,
+            backfill_db,
+            redaction_db,
+            undecrypted_db,
+            edit_db,
+            ocr_db,
+            member_db,
+            outbox_db,
+            sync_db,
+            gap_db,
+            inactive_db,
+            upgrade_db,
+            failed_outbox_db,
         })
     }
 
@@ -159,15 +707,49 @@ impl HeedStore {
         Ok(())
     }
 
-    pub fn move_entry(&self, old_room_id: &str, new_room_id: &str) -> Result<()> {
+    /// Record that a room was upgraded, indexing the new room like the old one.
+    ///
+    /// Returns `false` without recording anything if the old room isn't indexed.
+    pub fn add_upgrade(&self, old_room_id: &str, new_room_id: &str) -> Result<bool> {
+        let mut wtxn = self.env.write_txn()?;
+        let index = match self.index_db.get(&wtxn, old_room_id)? {
+            Some(index) => index.to_string(),
+            None => return Ok(false),
+        };
+        if self.index_db.get(&wtxn, new_room_id)?.is_none() {
+            let name = self
+                .name_db
+                .get(&wtxn, old_room_id)?
+                .unwrap_or(new_room_id)
+                .to_string();
+            self.index_db.put(&mut wtxn, new_room_id, &index)?;
+            self.name_db.put(&mut wtxn, new_room_id, &name)?;
+        }
+        self.upgrade_db.put(&mut wtxn, old_room_id, new_room_id)?;
+        wtxn.commit()?;
+        Ok(true)
+    }
+
+    /// Get the room that replaced a room.
+    pub fn get_successor(&self, room_id: &str) -> Result<Option<String>> {
         let rtxn = self.env.read_txn()?;
-        let index = self.index_db.get(&rtxn, old_room_id)?;
-        let name = self.name_db.get(&rtxn, old_room_id)?;
+        Ok(self
+            .upgrade_db
+            .get(&rtxn, room_id)?
+            .map(|room_id| room_id.to_string()))
+    }
+
+    /// Stop indexing a room, forgetting its state but keeping its indexed messages.
+    pub fn remove_entry(&self, room_id: &str) -> Result<bool> {
         let mut wtxn = self.env.write_txn()?;
-        self.index_db.put(&mut wtxn, new_room_id, index.unwrap())?;
-        self.name_db.put(&mut wtxn, new_room_id, name.unwrap())?;
+        let removed = self.index_db.delete(&mut wtxn, room_id)?;
+        self.name_db.delete(&mut wtxn, room_id)?;
+        self.backfill_db.delete(&mut wtxn, room_id)?;
+        self.redaction_db.delete(&mut wtxn, room_id)?;
+        self.member_db.delete(&mut wtxn, room_id)?;
+        self.inactive_db.delete(&mut wtxn, room_id)?;
         wtxn.commit()?;
-        Ok(())
+        Ok(removed)
     }
 
     pub fn update_entry(
@@ -208,6 +790,13 @@ impl HeedStore {
     pub fn get_rooms(&self) -> Result<Vec<RoomInfo>> {
         let mut result = Vec::new();
         let rtxn = self.env.read_txn()?;
+        let mut successors = HashMap::new();
+        let mut predecessors = HashMap::new();
+        for item in self.upgrade_db.iter(&rtxn)? {
+            let (old_room_id, new_room_id) = item?;
+            successors.insert(old_room_id, new_room_id);
+            predecessors.insert(new_room_id, old_room_id);
+        }
         let iter = self.index_db.iter(&rtxn)?;
         for item in iter {
             let (key, index_name) = item?;
@@ -215,12 +804,584 @@ impl HeedStore {
                 .name_db
                 .get(&rtxn, key)?
                 .map(|room_name| room_name.to_string());
+            let mut chain = Vec::new();
+            let mut room_id = key;
+            while let Some(predecessor) = predecessors.get(room_id) {
+                // Guard against upgrade loops.
+                if *predecessor == key || chain.contains(&predecessor.to_string()) {
+                    break;
+                }
+                chain.insert(0, predecessor.to_string());
+                room_id = predecessor;
+            }
             let info = RoomInfo {
+                room_id: key.to_string(),
                 index_name: index_name.to_string(),
                 room_name,
+                inactive: self.inactive_db.get(&rtxn, key)?,
+                predecessors: chain,
+                successor: successors.get(key).map(|room_id| room_id.to_string()),
             };
             result.push(info);
         }
         Ok(result)
     }
+
+    /// Mark a room as inactive, or as active again with `None`.
+    pub fn set_inactive(&self, room_id: &str, inactive: Option<&InactiveRoom>) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        match inactive {
+            Some(inactive) => self.inactive_db.put(&mut wtxn, room_id, inactive)?,
+            None => {
+                self.inactive_db.delete(&mut wtxn, room_id)?;
+            }
+        }
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn get_backfill_state(&self, room_id: &str) -> Result<Option<BackfillState>> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.backfill_db.get(&rtxn, room_id)?)
+    }
+
+    pub fn set_backfill_state(&self, room_id: &str, state: &BackfillState) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.backfill_db.put(&mut wtxn, room_id, state)?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn get_redaction_token(&self, room_id: &str) -> Result<Option<String>> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self
+            .redaction_db
+            .get(&rtxn, room_id)?
+            .map(|token| token.to_string()))
+    }
+
+    pub fn set_redaction_token(&self, room_id: &str, token: &str) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.redaction_db.put(&mut wtxn, room_id, token)?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn get_sync_token(&self) -> Result<Option<String>> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self
+            .sync_db
+            .get(&rtxn, "next_batch")?
+            .map(|token| token.to_string()))
+    }
+
+    /// Save the token of a processed sync response with the gaps it left.
+    pub fn set_sync_token(&self, token: &str, gaps: &[(String, SyncGap)]) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        for (room_id, gap) in gaps {
+            self.gap_db.put(&mut wtxn, &gap_key(room_id, gap), gap)?;
+        }
+        self.sync_db.put(&mut wtxn, "next_batch", token)?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn get_gaps(&self) -> Result<Vec<(String, SyncGap)>> {
+        let mut result = Vec::new();
+        let rtxn = self.env.read_txn()?;
+        for item in self.gap_db.iter(&rtxn)? {
+            let (key, gap) = item?;
+            if let Some((room_id, _)) = key.split_once(' ') {
+                result.push((room_id.to_string(), gap));
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn update_gap(&self, room_id: &str, gap: &SyncGap) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.gap_db.put(&mut wtxn, &gap_key(room_id, gap), gap)?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn remove_gap(&self, room_id: &str, gap: &SyncGap) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.gap_db.delete(&mut wtxn, &gap_key(room_id, gap))?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn add_undecrypted(&self, event_id: &str, event: &UndecryptedEvent) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.undecrypted_db.put(&mut wtxn, event_id, event)?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn remove_undecrypted(&self, event_id: &str) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.undecrypted_db.delete(&mut wtxn, event_id)?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn get_undecrypted(&self) -> Result<Vec<(String, UndecryptedEvent)>> {
+        let mut result = Vec::new();
+        let rtxn = self.env.read_txn()?;
+        for item in self.undecrypted_db.iter(&rtxn)? {
+            let (event_id, event) = item?;
+            result.push((event_id.to_string(), event));
+        }
+        Ok(result)
+    }
+
+    /// Record an edit of a message, returning all known edits sorted by time.
+    pub fn add_edit(&self, event_id: &str, edit: LuoxuEdit) -> Result<Vec<LuoxuEdit>> {
+        let mut wtxn = self.env.write_txn()?;
+        let mut edits = self.edit_db.get(&wtxn, event_id)?.unwrap_or_default();
+        if !edits.iter().any(|e| e.event_id == edit.event_id) {
+            edits.push(edit);
+            edits.sort_by_key(|e| e.timestamp);
+            self.edit_db.put(&mut wtxn, event_id, &edits)?;
+        }
+        wtxn.commit()?;
+        Ok(edits)
+    }
+
+    pub fn get_edits(&self, event_id: &str) -> Result<Vec<LuoxuEdit>> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.edit_db.get(&rtxn, event_id)?.unwrap_or_default())
+    }
+
+    /// Find the messages edited by some events, as pairs of edit and original event IDs.
+    ///
+    /// Edits are only stored by their original, so this scans every edited message.
+    pub fn get_edited_events(&self, edit_ids: &[String]) -> Result<Vec<(String, String)>> {
+        let rtxn = self.env.read_txn()?;
+        let mut edited = Vec::new();
+        for item in self.edit_db.iter(&rtxn)? {
+            let (original, edits) = item?;
+            for edit in edits {
+                if edit_ids.contains(&edit.event_id) {
+                    edited.push((edit.event_id, original.to_string()));
+                }
+            }
+        }
+        Ok(edited)
+    }
+
+    /// Forget an edit of a message, returning its remaining edits.
+    pub fn remove_edit(&self, event_id: &str, edit_id: &str) -> Result<Vec<LuoxuEdit>> {
+        let mut wtxn = self.env.write_txn()?;
+        let mut edits = self.edit_db.get(&wtxn, event_id)?.unwrap_or_default();
+        edits.retain(|e| e.event_id != edit_id);
+        if edits.is_empty() {
+            self.edit_db.delete(&mut wtxn, event_id)?;
+        } else {
+            self.edit_db.put(&mut wtxn, event_id, &edits)?;
+        }
+        wtxn.commit()?;
+        Ok(edits)
+    }
+
+    /// Queue an image for OCR, replacing a job of the same event.
+    pub fn put_ocr_job(&self, event_id: &str, job: &OcrJob) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.ocr_db.put(&mut wtxn, event_id, job)?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn remove_ocr_job(&self, event_id: &str) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.ocr_db.delete(&mut wtxn, event_id)?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn set_members(&self, room_id: &str, members: &[OwnedUserId]) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.member_db.put(&mut wtxn, room_id, &members.to_vec())?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    /// Record a user joining or leaving a room.
+    pub fn update_member(&self, room_id: &str, user_id: &OwnedUserId, joined: bool) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        let mut members = self.member_db.get(&wtxn, room_id)?.unwrap_or_default();
+        let position = members.iter().position(|member| member == user_id);
+        match (joined, position) {
+            (true, None) => members.push(user_id.clone()),
+            (false, Some(position)) => {
+                members.swap_remove(position);
+            }
+            _ => return Ok(()),
+        }
+        self.member_db.put(&mut wtxn, room_id, &members)?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn is_member(&self, room_id: &str, user_id: &UserId) -> Result<bool> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self
+            .member_db
+            .get(&rtxn, room_id)?
+            .is_some_and(|members| members.iter().any(|member| member == user_id)))
+    }
+
+    /// Get the indices of the rooms a user is joined to.
+    pub fn get_member_indices(&self, user_id: &UserId) -> Result<HashSet<String>> {
+        let mut result = HashSet::new();
+        for info in self.get_rooms()? {
+            if self.is_member(&info.room_id, user_id)? {
+                result.insert(info.index_name);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Get the rooms of an index whose messages a user can see, as a search filter.
+    ///
+    /// Users see the rooms they are joined to, with the rooms these replaced. Members of an
+    /// upgraded room don't see the rooms replacing it, as they may have been left out of them.
+    /// Returns `None` if they see none of them, and an empty filter if they see all of them.
+    pub fn get_room_filter(
+        &self,
+        user_id: &UserId,
+        index: &str,
+    ) -> Result<Option<Vec<OwnedRoomId>>> {
+        let rooms: Vec<_> = self
+            .get_rooms()?
+            .into_iter()
+            .filter(|info| info.index_name == index)
+            .collect();
+        let mut visible = HashSet::new();
+        for info in &rooms {
+            if self.is_member(&info.room_id, user_id)? {
+                visible.insert(info.room_id.clone());
+                visible.extend(info.predecessors.iter().cloned());
+            }
+        }
+        let filter = rooms
+            .iter()
+            .filter(|info| visible.contains(&info.room_id))
+            .map(|info| OwnedRoomId::try_from(info.room_id.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(match filter.len() {
+            0 => None,
+            len if len == rooms.len() => Some(vec![]),
+            _ => Some(filter),
+        })
+    }
+
+    /// Whether several rooms are indexed in an index.
+    pub fn is_shared_index(&self, index: &str) -> Result<bool> {
+        let rtxn = self.env.read_txn()?;
+        let mut count = 0;
+        for item in self.index_db.iter(&rtxn)? {
+            if item?.1 == index {
+                count += 1;
+            }
+        }
+        Ok(count > 1)
+    }
+
+    /// Queue changes of an index, replacing pending changes of the same messages.
+    ///
+    /// A pending deletion is kept over an update, as the update may have been read before
+    /// the message was redacted.
+    pub fn put_outbox(&self, index: &str, entries: &[OutboxEntry]) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        for entry in entries {
+            let key = outbox_key(index, entry.event_id());
+            if let OutboxEntry::AddOrUpdate(_) = entry {
+                if let Some(OutboxEntry::Delete(_)) = self.outbox_db.get(&wtxn, &key)? {
+                    continue;
+                }
+            }
+            self.outbox_db.put(&mut wtxn, &key, entry)?;
+        }
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn get_outbox(&self, index: &str, event_id: &KeyEventId) -> Result<Option<OutboxEntry>> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.outbox_db.get(&rtxn, &outbox_key(index, event_id))?)
+    }
+
+    /// Get up to `limit` pending changes with their index, except those of some indices.
+    pub fn get_outbox_entries(
+        &self,
+        limit: usize,
+        skipped: &HashSet<String>,
+    ) -> Result<Vec<(String, OutboxEntry)>> {
+        let mut result = Vec::new();
+        let rtxn = self.env.read_txn()?;
+        for item in self.outbox_db.iter(&rtxn)? {
+            if result.len() >= limit {
+                break;
+            }
+            let (key, entry) = item?;
+            if let Some((index, _)) = key.split_once(' ') {
+                if !skipped.contains(index) {
+                    result.push((index.to_string(), entry));
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Get up to `limit` pending changes of an index.
+    pub fn get_index_outbox(&self, index: &str, limit: usize) -> Result<Vec<OutboxEntry>> {
+        let rtxn = self.env.read_txn()?;
+        let mut result = Vec::new();
+        for item in self
+            .outbox_db
+            .prefix_iter(&rtxn, &format!("{} ", index))?
+            .take(limit)
+        {
+            result.push(item?.1);
+        }
+        Ok(result)
+    }
+
+    /// Move a change the search backend keeps rejecting out of the outbox, unless it has been
+    /// replaced in the meantime.
+    pub fn fail_outbox(&self, index: &str, entry: &OutboxEntry) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        let key = outbox_key(index, entry.event_id());
+        if let Some(current) = self.outbox_db.get(&wtxn, &key)? {
+            if serde_json::to_string(&current)? == serde_json::to_string(entry)? {
+                self.outbox_db.delete(&mut wtxn, &key)?;
+                self.failed_outbox_db.put(&mut wtxn, &key, entry)?;
+            }
+        }
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    /// Queue the changes moved out of the outbox again, returning how many there were.
+    pub fn requeue_failed_outbox(&self) -> Result<usize> {
+        let mut wtxn = self.env.write_txn()?;
+        let mut failed = Vec::new();
+        for item in self.failed_outbox_db.iter(&wtxn)? {
+            let (key, entry) = item?;
+            failed.push((key.to_string(), entry));
+        }
+        for (key, entry) in &failed {
+            // Newer changes of the same message take precedence.
+            if self.outbox_db.get(&wtxn, key)?.is_none() {
+                self.outbox_db.put(&mut wtxn, key, entry)?;
+            }
+        }
+        self.failed_outbox_db.clear(&mut wtxn)?;
+        wtxn.commit()?;
+        Ok(failed.len())
+    }
+
+    /// Remove written changes, unless they have been replaced in the meantime.
+    pub fn remove_outbox(&self, index: &str, entries: &[OutboxEntry]) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        for entry in entries {
+            let key = outbox_key(index, entry.event_id());
+            let current = self.outbox_db.get(&wtxn, &key)?;
+            if let Some(current) = current {
+                if serde_json::to_string(&current)? == serde_json::to_string(entry)? {
+                    self.outbox_db.delete(&mut wtxn, &key)?;
+                }
+            }
+        }
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn get_ocr_jobs(&self) -> Result<Vec<(String, OcrJob)>> {
+        let mut result = Vec::new();
+        let rtxn = self.env.read_txn()?;
+        for item in self.ocr_db.iter(&rtxn)? {
+            let (event_id, job) = item?;
+            result.push((event_id.to_string(), job));
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::UInt;
+
+    fn user(id: &str) -> OwnedUserId {
+        OwnedUserId::try_from(id).unwrap()
+    }
+
+    fn room(id: &str) -> OwnedRoomId {
+        OwnedRoomId::try_from(id).unwrap()
+    }
+
+    fn store(dir: &tempfile::TempDir) -> HeedStore {
+        HeedStore::new(dir.path().to_str().unwrap()).unwrap()
+    }
+
+    fn event_id(id: &str) -> KeyEventId {
+        OwnedEventId::try_from(id).unwrap().into()
+    }
+
+    fn update(id: &str, body: &str) -> OutboxEntry {
+        OutboxEntry::AddOrUpdate(Box::new(LuoxuMessage {
+            body: body.to_string(),
+            event_id: event_id(id),
+            external_url: None,
+            user_id: user("@alice:example.org"),
+            user_display_name: None,
+            user_avatar: None,
+            timestamp: MilliSecondsSinceUnixEpoch(UInt::from(1u32)),
+            room_id: room("!room:example.org"),
+            ocr_body: None,
+            normalized_body: None,
+            edited_at: None,
+            has: vec![],
+        }))
+    }
+
+    /// The body of a pending update, or `None` for a pending deletion.
+    fn pending(store: &HeedStore, id: &str) -> Option<Option<String>> {
+        store
+            .get_outbox("index", &event_id(id))
+            .unwrap()
+            .map(|entry| match entry {
+                OutboxEntry::AddOrUpdate(msg) => Some(msg.body),
+                OutboxEntry::Delete(_) => None,
+            })
+    }
+
+    #[test]
+    fn outbox_keeps_pending_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(&dir);
+        store.put_outbox("index", &[update("$1", "one")]).unwrap();
+        store
+            .put_outbox("index", &[OutboxEntry::Delete(event_id("$1"))])
+            .unwrap();
+        // An update read before the redaction must not bring the message back.
+        store
+            .put_outbox("index", &[update("$1", "edited")])
+            .unwrap();
+        assert_eq!(pending(&store, "$1"), Some(None));
+
+        store.put_outbox("index", &[update("$2", "two")]).unwrap();
+        store
+            .put_outbox("index", &[update("$2", "edited")])
+            .unwrap();
+        assert_eq!(pending(&store, "$2"), Some(Some("edited".to_string())));
+    }
+
+    #[test]
+    fn outbox_keeps_changes_replaced_during_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(&dir);
+        store.put_outbox("index", &[update("$1", "one")]).unwrap();
+        let read: Vec<_> = store
+            .get_outbox_entries(10, &HashSet::new())
+            .unwrap()
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect();
+        store
+            .put_outbox("index", &[update("$1", "edited")])
+            .unwrap();
+        store.remove_outbox("index", &read).unwrap();
+        assert_eq!(pending(&store, "$1"), Some(Some("edited".to_string())));
+
+        store
+            .remove_outbox("index", &[update("$1", "edited")])
+            .unwrap();
+        assert_eq!(pending(&store, "$1"), None);
+    }
+
+    #[test]
+    fn requeue_keeps_newer_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(&dir);
+        store
+            .put_outbox("index", &[update("$1", "one"), update("$2", "two")])
+            .unwrap();
+        store.fail_outbox("index", &update("$1", "one")).unwrap();
+        store.fail_outbox("index", &update("$2", "two")).unwrap();
+        assert_eq!(pending(&store, "$1"), None);
+        assert_eq!(pending(&store, "$2"), None);
+
+        store
+            .put_outbox("index", &[OutboxEntry::Delete(event_id("$1"))])
+            .unwrap();
+        assert_eq!(store.requeue_failed_outbox().unwrap(), 2);
+        assert_eq!(pending(&store, "$1"), Some(None));
+        assert_eq!(pending(&store, "$2"), Some(Some("two".to_string())));
+        assert_eq!(store.requeue_failed_outbox().unwrap(), 0);
+
+        // A change replaced since it was read stays in the outbox.
+        store
+            .put_outbox("index", &[update("$2", "edited")])
+            .unwrap();
+        store.fail_outbox("index", &update("$2", "two")).unwrap();
+        assert_eq!(pending(&store, "$2"), Some(Some("edited".to_string())));
+        assert_eq!(store.requeue_failed_outbox().unwrap(), 0);
+    }
+
+    #[test]
+    fn room_filter_only_exposes_predecessors() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(&dir);
+        store.add_entry("!old:example.org", "group", None).unwrap();
+        assert!(store
+            .add_upgrade("!old:example.org", "!new:example.org")
+            .unwrap());
+        store
+            .add_entry("!other:example.org", "group", None)
+            .unwrap();
+        let old_member = user("@old:example.org");
+        let new_member = user("@new:example.org");
+        let everywhere = user("@everywhere:example.org");
+        store
+            .set_members(
+                "!old:example.org",
+                &[old_member.clone(), new_member.clone()],
+            )
+            .unwrap();
+        store
+            .set_members("!new:example.org", std::slice::from_ref(&new_member))
+            .unwrap();
+        for room_id in ["!old:example.org", "!new:example.org", "!other:example.org"] {
+            store.update_member(room_id, &everywhere, true).unwrap();
+        }
+
+        // Kept out of the upgraded room, so only the old room is visible.
+        assert_eq!(
+            store.get_room_filter(&old_member, "group").unwrap(),
+            Some(vec![room("!old:example.org")])
+        );
+        let mut rooms = store
+            .get_room_filter(&new_member, "group")
+            .unwrap()
+            .unwrap();
+        rooms.sort();
+        assert_eq!(
+            rooms,
+            vec![room("!new:example.org"), room("!old:example.org")]
+        );
+        assert_eq!(
+            store.get_room_filter(&everywhere, "group").unwrap(),
+            Some(vec![])
+        );
+        assert_eq!(
+            store
+                .get_room_filter(&user("@nobody:example.org"), "group")
+                .unwrap(),
+            None
+        );
+    }
 }
@@ -1,28 +1,36 @@
 #![forbid(unsafe_code)]
 use anyhow::Context;
-use luoxu_rs::LuoxuConfig;
-use matrix_sdk::Session;
-use std::fs;
+use clap::Parser;
+use luoxu_rs::{get_session, save_session, LuoxuConfig};
+use std::time::Duration;
 use tokio::{signal, task::JoinHandle};
 use tokio_util::sync::CancellationToken;
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::EnvFilter;
 
+use crate::admin::Cli;
 use crate::bot::{LoginType, LuoxuBot};
+use crate::outbox::drain_outbox;
 
+mod admin;
+mod backfill;
 mod bot;
 mod callbacks;
+mod commands;
+mod encryption;
+mod invites;
+mod members;
+mod ocr;
+mod outbox;
+mod spaces;
+mod upgrades;
 
-static SESSION_JSON_FILE: &str = "credentials.json";
-
-fn get_session() -> anyhow::Result<Session> {
-    Ok(serde_json::from_str::<Session>(&fs::read_to_string(
-        SESSION_JSON_FILE,
-    )?)?)
-}
+/// How long pending messages are written on shutdown before giving up.
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
     let filter = EnvFilter::from_default_env()
         .add_directive(LevelFilter::WARN.into())
         .add_directive("luoxu_rs=debug".parse()?);
@@ -35,6 +43,9 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     let config = LuoxuConfig::get_config().context("Failed to read config file")?;
+    if let Some(command) = cli.command {
+        return admin::run(command, config).await;
+    }
 
     let session = get_session();
 
@@ -53,7 +64,7 @@ async fn main() -> anyhow::Result<()> {
 
     let bot = LuoxuBot::new(config).await?;
     if let Some(session) = bot.login(login).await? {
-        fs::write(SESSION_JSON_FILE, serde_json::to_string(&session)?)?;
+        save_session(&session)?;
     }
 
     {
@@ -88,6 +99,7 @@ async fn main() -> anyhow::Result<()> {
     // Run it
     bot.update_state().await?;
     bot.update_indices().await?;
+    let context = bot.context();
     let task: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
         tokio::select! {
             _ = bot_cts.cancelled() => {
@@ -100,5 +112,13 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
-    task.await?
+    task.await??;
+
+    // Write the buffered messages, anything left stays in the outbox for the next start.
+    match tokio::time::timeout(SHUTDOWN_FLUSH_TIMEOUT, drain_outbox(&context)).await {
+        Ok(Ok(written)) => tracing::info!("Wrote {} pending changes", written),
+        Ok(Err(e)) => tracing::warn!("Writing pending changes failed: {}", e),
+        Err(_) => tracing::warn!("Writing pending changes timed out"),
+    }
+    Ok(())
 }
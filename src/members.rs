@@ -0,0 +1,152 @@
+use luoxu_rs::{InactiveReason, InactiveRoom, LuoxuBotContext, RoomInfo};
+use matrix_sdk::event_handler::Ctx;
+use matrix_sdk::room::{Joined, Room};
+use matrix_sdk::ruma::events::room::member::{MembershipState, SyncRoomMemberEvent};
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedRoomId, RoomId};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often rooms are checked for an expired grace period.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Keep the members of indexed rooms up to date, for the web API to authorize users.
+pub async fn on_room_member(
+    ev: SyncRoomMemberEvent,
+    room: Room,
+    client: matrix_sdk::Client,
+    ctx: Ctx<Arc<LuoxuBotContext>>,
+) -> anyhow::Result<()> {
+    if let Ok(Some(_)) = ctx.store.get_index(room.room_id().into()) {
+        let joined = *ev.membership() == MembershipState::Join;
+        ctx.store
+            .update_member(room.room_id().as_str(), ev.state_key(), joined)?;
+        if Some(ev.state_key().as_ref()) == client.user_id() {
+            update_own_membership(&ctx, room.room_id(), &ev)?;
+        }
+    }
+    Ok(())
+}
+
+/// Mark a room as inactive when the bot leaves or is removed from it, and active again when it rejoins.
+fn update_own_membership(
+    ctx: &LuoxuBotContext,
+    room_id: &RoomId,
+    ev: &SyncRoomMemberEvent,
+) -> anyhow::Result<()> {
+    let reason = match ev.membership() {
+        MembershipState::Join => return ctx.store.set_inactive(room_id.as_str(), None),
+        MembershipState::Leave if ev.sender() == ev.state_key() => InactiveReason::Left,
+        MembershipState::Leave => InactiveReason::Kicked,
+        MembershipState::Ban => InactiveReason::Banned,
+        _ => return Ok(()),
+    };
+    let inactive = InactiveRoom {
+        reason,
+        message: ev.as_original().and_then(|ev| ev.content.reason.clone()),
+        since: ev.origin_server_ts(),
+    };
+    tracing::warn!(
+        "No longer indexing {}, {:?} by {}",
+        room_id,
+        reason,
+        ev.sender()
+    );
+    ctx.store.set_inactive(room_id.as_str(), Some(&inactive))
+}
+
+/// Save the full member list of every indexed room.
+pub async fn sync_members(
+    client: matrix_sdk::Client,
+    ctx: Arc<LuoxuBotContext>,
+) -> anyhow::Result<()> {
+    for info in ctx.store.get_rooms()? {
+        let room_id = <&RoomId>::try_from(info.room_id.as_str())?;
+        let room = match client.get_room(room_id) {
+            Some(Room::Joined(room)) => room,
+            // Removed while the membership events were not seen.
+            Some(Room::Left(_)) if info.inactive.is_none() => {
+                let inactive = InactiveRoom {
+                    reason: InactiveReason::Left,
+                    message: None,
+                    since: MilliSecondsSinceUnixEpoch::now(),
+                };
+                ctx.store.set_inactive(room_id.as_str(), Some(&inactive))?;
+                continue;
+            }
+            _ => continue,
+        };
+        if let Err(e) = sync_room_members(&ctx, &room).await {
+            tracing::warn!("Fetching members of {} failed: {}", room_id, e);
+        }
+    }
+    Ok(())
+}
+
+/// Save the full member list of a room.
+pub async fn sync_room_members(ctx: &LuoxuBotContext, room: &Joined) -> anyhow::Result<()> {
+    let members: Vec<_> = room
+        .joined_members()
+        .await?
+        .iter()
+        .map(|member| member.user_id().to_owned())
+        .collect();
+    ctx.store.set_members(room.room_id().as_str(), &members)
+}
+
+/// Delete the messages of inactive rooms once their grace period is over.
+pub async fn expire_inactive_loop(ctx: Arc<LuoxuBotContext>, grace: Duration) {
+    loop {
+        if let Err(e) = expire_inactive(&ctx, grace).await {
+            tracing::warn!("Deleting inactive rooms failed: {}", e);
+        }
+        tokio::time::sleep(EXPIRE_INTERVAL).await;
+    }
+}
+
+async fn expire_inactive(ctx: &LuoxuBotContext, grace: Duration) -> anyhow::Result<()> {
+    let rooms = ctx.store.get_rooms()?;
+    for info in &rooms {
+        let expired = info.inactive.as_ref().is_some_and(|inactive| {
+            inactive
+                .since
+                .to_system_time()
+                .and_then(|since| since.elapsed().ok())
+                .is_some_and(|elapsed| elapsed >= grace)
+        });
+        if !expired {
+            continue;
+        }
+        let shared = rooms
+            .iter()
+            .any(|other| other.room_id != info.room_id && other.index_name == info.index_name);
+        // Keep the room to retry next time, without holding up the other rooms.
+        if let Err(e) = expire_room(ctx, info, shared).await {
+            tracing::warn!("Deleting the messages of {} failed: {:#}", info.room_id, e);
+        }
+    }
+    Ok(())
+}
+
+/// Delete the messages of an inactive room, then stop indexing it.
+async fn expire_room(ctx: &LuoxuBotContext, info: &RoomInfo, shared: bool) -> anyhow::Result<()> {
+    if shared {
+        let room_id = OwnedRoomId::try_from(info.room_id.as_str())?;
+        let event_ids: Vec<_> = ctx
+            .search
+            .documents(&info.index_name, &[room_id])
+            .await?
+            .into_iter()
+            .map(|msg| msg.event_id)
+            .collect();
+        ctx.delete_messages(&info.index_name, &event_ids)?;
+    } else {
+        ctx.search.delete_index(&info.index_name).await?;
+    }
+    ctx.store.remove_entry(&info.room_id)?;
+    tracing::info!(
+        "Deleted the messages of {} from {} after its grace period",
+        info.room_id,
+        info.index_name
+    );
+    Ok(())
+}
@@ -0,0 +1,37 @@
+//! Conversion between Chinese variants, so Traditional and Simplified Chinese match each other.
+
+use std::collections::HashMap;
+use zhconv::{zhconv, Variant};
+
+use crate::LuoxuMessage;
+
+/// Converts the text of an index into a single Chinese variant.
+#[derive(Clone, Debug, Default)]
+pub struct Normalizer {
+    variants: HashMap<String, Variant>,
+}
+
+impl Normalizer {
+    pub fn new(variants: HashMap<String, Variant>) -> Self {
+        Normalizer { variants }
+    }
+
+    /// Convert text to the variant of an index, `None` if the index isn't normalized.
+    pub fn normalize(&self, index: &str, text: &str) -> Option<String> {
+        let variant = self.variants.get(index)?;
+        Some(zhconv(text, *variant))
+    }
+
+    /// Convert a query to the variant of an index.
+    pub fn normalize_query(&self, index: &str, query: &str) -> String {
+        self.normalize(index, query)
+            .unwrap_or_else(|| query.to_string())
+    }
+
+    /// Fill in the normalized body of a message, keeping it empty when nothing changed.
+    pub fn normalize_message(&self, index: &str, msg: &mut LuoxuMessage) {
+        msg.normalized_body = self
+            .normalize(index, &msg.body)
+            .filter(|normalized| *normalized != msg.body);
+    }
+}
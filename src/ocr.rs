@@ -0,0 +1,109 @@
+use anyhow::bail;
+use luoxu_rs::{KeyEventId, LuoxuBotContext, LuoxuConfigOcr, OcrJob};
+use matrix_sdk::media::{MediaFormat, MediaRequest};
+use matrix_sdk::ruma::OwnedEventId;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// Interval between checks of the OCR queue.
+const OCR_INTERVAL: Duration = Duration::from_secs(60);
+/// Number of attempts before giving up on an image.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Recognize text in an image with Tesseract.
+async fn recognize(settings: &LuoxuConfigOcr, image: Vec<u8>) -> anyhow::Result<String> {
+    let mut child = Command::new(&settings.command)
+        .args(["stdin", "stdout", "-l", &settings.languages])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    // Write from another task, so Tesseract never blocks on a full stdout pipe.
+    let writer = tokio::spawn(async move { stdin.write_all(&image).await });
+    let output = child.wait_with_output().await?;
+    writer.await??;
+    if !output.status.success() {
+        bail!(
+            "{} exited with {}: {}",
+            settings.command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Recognize the text of a queued image and store it with its message.
+async fn process_job(
+    client: &matrix_sdk::Client,
+    ctx: &LuoxuBotContext,
+    settings: &LuoxuConfigOcr,
+    event_id: &str,
+    job: &OcrJob,
+) -> anyhow::Result<()> {
+    let key: KeyEventId = OwnedEventId::try_from(event_id)?.into();
+    // Skip images whose message has been redacted in the meantime.
+    if ctx.get_message(&job.index, &key).await?.is_none() {
+        return Ok(());
+    }
+    let request = MediaRequest {
+        source: job.source.clone(),
+        format: MediaFormat::File,
+    };
+    let image = client.media().get_media_content(&request, false).await?;
+    let text = recognize(settings, image).await?;
+    if text.is_empty() {
+        return Ok(());
+    }
+    let text = ctx.normalizer.normalize(&job.index, &text).unwrap_or(text);
+    // Fetch the message again, it may have been edited while recognizing.
+    if let Some(mut msg) = ctx.get_message(&job.index, &key).await? {
+        msg.ocr_body = Some(text);
+        ctx.add_messages(&job.index, vec![msg])?;
+    }
+    Ok(())
+}
+
+/// Process every queued image, keeping failed ones for a few more attempts.
+pub async fn process_ocr_queue(
+    client: &matrix_sdk::Client,
+    ctx: &LuoxuBotContext,
+    settings: &LuoxuConfigOcr,
+) -> anyhow::Result<()> {
+    for (event_id, mut job) in ctx.store.get_ocr_jobs()? {
+        match process_job(client, ctx, settings, &event_id, &job).await {
+            Ok(()) => ctx.store.remove_ocr_job(&event_id)?,
+            Err(e) => {
+                job.attempts += 1;
+                if job.attempts >= MAX_ATTEMPTS {
+                    tracing::warn!("Giving up recognizing text in {}: {}", event_id, e);
+                    ctx.store.remove_ocr_job(&event_id)?;
+                } else {
+                    tracing::debug!("Recognizing text in {} failed: {}", event_id, e);
+                    ctx.store.put_ocr_job(&event_id, &job)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Periodically recognize text in queued images.
+pub async fn ocr_loop(
+    client: matrix_sdk::Client,
+    ctx: Arc<LuoxuBotContext>,
+    settings: LuoxuConfigOcr,
+) {
+    let mut interval = tokio::time::interval(OCR_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = process_ocr_queue(&client, &ctx, &settings).await {
+            tracing::warn!("Processing the OCR queue failed: {}", e);
+        }
+    }
+}
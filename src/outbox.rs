@@ -0,0 +1,212 @@
+use luoxu_rs::{LuoxuBotContext, OutboxEntry};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Delay before retrying after the first failure, doubled after every other one.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// Failed attempts of an index after which its changes are written one by one, to move
+/// those the backend rejects out of the way.
+const ISOLATE_AFTER: u32 = 8;
+
+/// The outcome of writing a batch of the outbox.
+pub struct Flush {
+    /// Number of changes read from the outbox.
+    pub read: usize,
+    /// Number of changes written.
+    pub written: usize,
+    /// The indices written without errors.
+    pub succeeded: Vec<String>,
+    /// The indices that failed to be written, with their error.
+    pub failed: Vec<(String, anyhow::Error)>,
+}
+
+/// Consecutive failures of an index.
+struct Retry {
+    attempts: u32,
+    at: Instant,
+}
+
+/// Write the changes of the outbox to the search backend, retrying while it fails.
+///
+/// An index that fails is retried with backoff while the other indices are written.
+pub async fn outbox_loop(ctx: Arc<LuoxuBotContext>) {
+    let mut retries: HashMap<String, Retry> = HashMap::new();
+    loop {
+        let now = Instant::now();
+        let skipped: HashSet<_> = retries
+            .iter()
+            .filter(|(_, retry)| retry.at > now)
+            .map(|(index, _)| index.clone())
+            .collect();
+        let flush = match flush_outbox(&ctx, &skipped).await {
+            Ok(flush) => flush,
+            Err(e) => {
+                tracing::warn!("Reading the outbox failed: {}", e);
+                tokio::time::sleep(MAX_BACKOFF).await;
+                continue;
+            }
+        };
+        for index in &flush.succeeded {
+            retries.remove(index);
+        }
+        for (index, e) in flush.failed {
+            let retry = retries.entry(index.clone()).or_insert(Retry {
+                attempts: 0,
+                at: now,
+            });
+            retry.attempts += 1;
+            if retry.attempts >= ISOLATE_AFTER {
+                match isolate_failures(&ctx, &index).await {
+                    Ok(moved) => {
+                        if moved > 0 {
+                            tracing::warn!(
+                                "Moved {} changes of {} out of the outbox",
+                                moved,
+                                index
+                            );
+                        }
+                        retries.remove(&index);
+                        continue;
+                    }
+                    Err(e) => tracing::warn!("Isolating the failures of {} failed: {}", index, e),
+                }
+            }
+            let backoff = (MIN_BACKOFF * 2u32.pow(retry.attempts.min(16) - 1)).min(MAX_BACKOFF);
+            retry.at = Instant::now() + backoff;
+            tracing::warn!(
+                "Writing to index {} failed, retrying in {:?}: {:#}",
+                index,
+                backoff,
+                e
+            );
+        }
+        // More changes may be waiting behind a full batch.
+        if flush.read >= ctx.outbox.batch_size {
+            continue;
+        }
+        match retries.values().map(|retry| retry.at).min() {
+            Some(at) => {
+                tokio::select! {
+                    _ = ctx.outbox.wait_batch() => {}
+                    _ = tokio::time::sleep_until(at) => {}
+                }
+            }
+            None => ctx.outbox.wait_batch().await,
+        }
+    }
+}
+
+/// Write a batch of changes of the indices not skipped.
+///
+/// Changes are only removed from the outbox once the backend confirmed them.
+pub async fn flush_outbox(
+    ctx: &LuoxuBotContext,
+    skipped: &HashSet<String>,
+) -> anyhow::Result<Flush> {
+    let mut indices: BTreeMap<String, Vec<OutboxEntry>> = BTreeMap::new();
+    let entries = ctx
+        .store
+        .get_outbox_entries(ctx.outbox.batch_size, skipped)?;
+    let mut flush = Flush {
+        read: entries.len(),
+        written: 0,
+        succeeded: Vec::new(),
+        failed: Vec::new(),
+    };
+    for (index, entry) in entries {
+        indices.entry(index).or_default().push(entry);
+    }
+    for (index, entries) in indices {
+        // Keep writing the other indices when one of them fails.
+        match write_entries(ctx, &index, &entries).await {
+            Ok(()) => {
+                ctx.store.remove_outbox(&index, &entries)?;
+                flush.written += entries.len();
+                flush.succeeded.push(index);
+            }
+            Err(e) => flush.failed.push((index, e)),
+        }
+    }
+    Ok(flush)
+}
+
+async fn write_entries(
+    ctx: &LuoxuBotContext,
+    index: &str,
+    entries: &[OutboxEntry],
+) -> anyhow::Result<()> {
+    let mut messages = Vec::new();
+    let mut deleted = Vec::new();
+    for entry in entries {
+        match entry {
+            OutboxEntry::AddOrUpdate(msg) => messages.push((**msg).clone()),
+            OutboxEntry::Delete(event_id) => deleted.push(event_id.clone()),
+        }
+    }
+    if !messages.is_empty() {
+        ctx.search.add_or_update(index, &messages).await?;
+    }
+    if !deleted.is_empty() {
+        ctx.search.delete(index, &deleted).await?;
+    }
+    Ok(())
+}
+
+/// Write the changes of a failing index one by one, moving those that fail out of the
+/// outbox, and returning how many were moved.
+///
+/// Nothing is moved while the backend itself is unreachable.
+async fn isolate_failures(ctx: &LuoxuBotContext, index: &str) -> anyhow::Result<usize> {
+    // There is nothing to delete from an index that no longer exists.
+    let missing = !ctx.search.list_indices().await?.iter().any(|i| i == index);
+    let mut moved = 0;
+    for entry in ctx.store.get_index_outbox(index, ctx.outbox.batch_size)? {
+        let entries = [entry];
+        if missing && matches!(entries[0], OutboxEntry::Delete(_)) {
+            ctx.store.remove_outbox(index, &entries)?;
+            continue;
+        }
+        match write_entries(ctx, index, &entries).await {
+            Ok(()) => ctx.store.remove_outbox(index, &entries)?,
+            Err(e) => {
+                tracing::warn!(
+                    "Giving up writing {} to {}: {:#}",
+                    entries[0].event_id().event_id(),
+                    index,
+                    e
+                );
+                ctx.store.fail_outbox(index, &entries[0])?;
+                moved += 1;
+            }
+        }
+    }
+    Ok(moved)
+}
+
+/// Write everything left in the outbox, e.g. before shutting down.
+///
+/// Indices that fail are skipped, their changes are kept for the next start.
+pub async fn drain_outbox(ctx: &LuoxuBotContext) -> anyhow::Result<usize> {
+    let mut written = 0;
+    let mut failed = HashSet::new();
+    loop {
+        let flush = flush_outbox(ctx, &failed).await?;
+        written += flush.written;
+        for (index, e) in flush.failed {
+            tracing::warn!("Writing to index {} failed: {:#}", index, e);
+            failed.insert(index);
+        }
+        if flush.read == 0 {
+            break;
+        }
+    }
+    if !failed.is_empty() {
+        let mut failed: Vec<_> = failed.into_iter().collect();
+        failed.sort();
+        anyhow::bail!("Writing to {} failed", failed.join(", "));
+    }
+    Ok(written)
+}
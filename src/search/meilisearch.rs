@@ -0,0 +1,475 @@
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use matrix_sdk::ruma::OwnedRoomId;
+use meilisearch_sdk::client::Client;
+use meilisearch_sdk::documents::DocumentsQuery;
+use meilisearch_sdk::errors::{Error, ErrorCode};
+use meilisearch_sdk::indexes::{Index, IndexesQuery};
+use meilisearch_sdk::search::{MultiSearchQuery, SearchResults as MeiliSearchResults};
+use meilisearch_sdk::settings::{MinWordSizeForTypos, Settings, TypoToleranceSettings};
+use meilisearch_sdk::task_info::TaskInfo;
+use meilisearch_sdk::Selectors;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::{
+    highlight_body, SearchBackend, SearchFilter, SearchHit, SearchQuery, SearchResults, SearchSort,
+};
+use crate::{KeyEventId, LuoxuMessage};
+
+/// Number of documents or indices fetched per request when listing them.
+const PAGE_SIZE: usize = 1000;
+/// How long to wait for index creation and settings updates, which reindex the documents.
+const TASK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Attributes the filters of queries rely on.
+const FILTERABLE_ATTRIBUTES: [&str; 4] = ["user_id", "timestamp", "has", "room_id"];
+const SORTABLE_ATTRIBUTES: [&str; 1] = ["timestamp"];
+
+/// Search backend using a Meilisearch server.
+pub struct MeilisearchBackend {
+    pub client: Client,
+    /// Settings of every index.
+    settings: IndexSettings,
+    /// Settings of specific indices, overriding `settings`.
+    index_settings: HashMap<String, IndexSettings>,
+}
+
+/// Index settings declared in the configuration, the ones not set are left as they are.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct IndexSettings {
+    pub ranking_rules: Option<Vec<String>>,
+    pub stop_words: Option<Vec<String>>,
+    pub synonyms: Option<HashMap<String, Vec<String>>>,
+    pub typo_tolerance: Option<TypoTolerance>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct TypoTolerance {
+    pub enabled: Option<bool>,
+    pub min_word_size_for_one_typo: Option<u8>,
+    pub min_word_size_for_two_typos: Option<u8>,
+    pub disable_on_words: Option<Vec<String>>,
+    pub disable_on_attributes: Option<Vec<String>>,
+}
+
+impl IndexSettings {
+    /// Fill the settings not set with the given defaults.
+    fn or(self, defaults: &IndexSettings) -> IndexSettings {
+        IndexSettings {
+            ranking_rules: self
+                .ranking_rules
+                .or_else(|| defaults.ranking_rules.clone()),
+            stop_words: self.stop_words.or_else(|| defaults.stop_words.clone()),
+            synonyms: self.synonyms.or_else(|| defaults.synonyms.clone()),
+            typo_tolerance: self
+                .typo_tolerance
+                .or_else(|| defaults.typo_tolerance.clone()),
+        }
+    }
+}
+
+impl TypoTolerance {
+    /// Apply the declared settings over the live ones.
+    fn apply(&self, live: &TypoToleranceSettings) -> TypoToleranceSettings {
+        let mut result = live.clone();
+        if let Some(enabled) = self.enabled {
+            result.enabled = Some(enabled);
+        }
+        if self.min_word_size_for_one_typo.is_some() || self.min_word_size_for_two_typos.is_some() {
+            let live = live.min_word_size_for_typos.as_ref();
+            result.min_word_size_for_typos = Some(MinWordSizeForTypos {
+                one_typo: self
+                    .min_word_size_for_one_typo
+                    .or_else(|| live.and_then(|size| size.one_typo)),
+                two_typos: self
+                    .min_word_size_for_two_typos
+                    .or_else(|| live.and_then(|size| size.two_typos)),
+            });
+        }
+        if let Some(words) = &self.disable_on_words {
+            result.disable_on_words = Some(words.clone());
+        }
+        if let Some(attributes) = &self.disable_on_attributes {
+            result.disable_on_attributes = Some(attributes.clone());
+        }
+        result
+    }
+}
+
+impl MeilisearchBackend {
+    pub fn new(url: &str, key: &str) -> Self {
+        MeilisearchBackend {
+            client: Client::new(url, Some(key)),
+            settings: IndexSettings::default(),
+            index_settings: HashMap::new(),
+        }
+    }
+
+    /// Declare the settings of indices, applied by `create_index`.
+    pub fn with_settings(
+        mut self,
+        settings: IndexSettings,
+        index_settings: HashMap<String, IndexSettings>,
+    ) -> Self {
+        self.settings = settings;
+        self.index_settings = index_settings;
+        self
+    }
+
+    /// Wait for a task to complete, failing if it failed.
+    async fn wait(&self, task: TaskInfo, action: &str) -> Result<()> {
+        let task = task
+            .wait_for_completion(&self.client, None, Some(TASK_TIMEOUT))
+            .await?;
+        if task.is_failure() {
+            bail!("{} failed: {}", action, task.unwrap_failure());
+        }
+        Ok(())
+    }
+
+    /// Get the settings of an index that differ from the declared ones, and their names.
+    fn settings_changes(&self, index: &str, live: &Settings) -> (Settings, Vec<&'static str>) {
+        let declared = self
+            .index_settings
+            .get(index)
+            .cloned()
+            .unwrap_or_default()
+            .or(&self.settings);
+        let mut settings = Settings::new();
+        let mut changes = Vec::new();
+        let filterable = FILTERABLE_ATTRIBUTES.map(String::from).to_vec();
+        if !same_set(live.filterable_attributes.as_deref(), &filterable) {
+            settings.filterable_attributes = Some(filterable);
+            changes.push("filterable attributes");
+        }
+        let sortable = SORTABLE_ATTRIBUTES.map(String::from).to_vec();
+        if !same_set(live.sortable_attributes.as_deref(), &sortable) {
+            settings.sortable_attributes = Some(sortable);
+            changes.push("sortable attributes");
+        }
+        if let Some(ranking_rules) = declared.ranking_rules {
+            if live.ranking_rules.as_ref() != Some(&ranking_rules) {
+                settings.ranking_rules = Some(ranking_rules);
+                changes.push("ranking rules");
+            }
+        }
+        if let Some(stop_words) = declared.stop_words {
+            if !same_set(live.stop_words.as_deref(), &stop_words) {
+                settings.stop_words = Some(stop_words);
+                changes.push("stop words");
+            }
+        }
+        if let Some(synonyms) = declared.synonyms {
+            let sorted = |synonyms: &HashMap<String, Vec<String>>| {
+                let mut synonyms: Vec<_> = synonyms
+                    .iter()
+                    .map(|(word, words)| {
+                        let mut words = words.clone();
+                        words.sort();
+                        (word.clone(), words)
+                    })
+                    .collect();
+                synonyms.sort();
+                synonyms
+            };
+            if live.synonyms.as_ref().map(sorted) != Some(sorted(&synonyms)) {
+                settings.synonyms = Some(synonyms);
+                changes.push("synonyms");
+            }
+        }
+        if let Some(typo_tolerance) = declared.typo_tolerance {
+            let live = live.typo_tolerance.clone().unwrap_or_default();
+            let typo_tolerance = typo_tolerance.apply(&live);
+            if typo_tolerance != live {
+                settings.typo_tolerance = Some(typo_tolerance);
+                changes.push("typo tolerance");
+            }
+        }
+        (settings, changes)
+    }
+
+    /// Build a Meilisearch query for an index.
+    fn build_query<'a>(
+        index: &'a Index,
+        query: &'a SearchQuery,
+        filter: &'a str,
+    ) -> meilisearch_sdk::search::SearchQuery<'a> {
+        let mut search = index.search();
+        search
+            .with_query(&query.query)
+            .with_attributes_to_search_on(&["body", "normalized_body", "ocr_body"])
+            .with_show_ranking_score(true)
+            .with_offset(query.offset)
+            .with_limit(query.limit);
+        match query.sort {
+            SearchSort::Newest => {
+                search.with_sort(&["timestamp:desc"]);
+            }
+            SearchSort::Oldest => {
+                search.with_sort(&["timestamp:asc"]);
+            }
+            SearchSort::Relevance => {}
+        }
+        if !filter.is_empty() {
+            search.with_filter(filter);
+        }
+        if let Some((pre_tag, post_tag)) = &query.highlight {
+            search
+                .with_attributes_to_highlight(Selectors::Some(&["body", "ocr_body"]))
+                .with_show_matches_position(true)
+                .with_highlight_pre_tag(pre_tag)
+                .with_highlight_post_tag(post_tag);
+        }
+        search
+    }
+}
+
+/// Translate a filter into a Meilisearch filter expression.
+fn filter_expression(filter: &SearchFilter) -> String {
+    let mut conditions = Vec::new();
+    if let Some(before) = filter.before {
+        conditions.push(format!("timestamp < {}", before.0));
+    }
+    if let Some(after) = filter.after {
+        conditions.push(format!("timestamp > {}", after.0));
+    }
+    if let Some(sender) = &filter.sender {
+        conditions.push(format!("user_id = {}", quote(sender.as_str())));
+    }
+    for kind in &filter.has {
+        conditions.push(format!("has = {}", quote(kind.as_str())));
+    }
+    if !filter.rooms.is_empty() {
+        let rooms: Vec<_> = filter
+            .rooms
+            .iter()
+            .map(|room_id| quote(room_id.as_str()))
+            .collect();
+        conditions.push(format!("room_id IN [{}]", rooms.join(", ")));
+    }
+    conditions.join(" AND ")
+}
+
+/// Quote a value of a filter expression, as user IDs may contain quotes.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn convert_results(
+    results: MeiliSearchResults<LuoxuMessage>,
+    query: &SearchQuery,
+) -> SearchResults {
+    SearchResults {
+        estimated_total_hits: results
+            .estimated_total_hits
+            .or(results.total_hits)
+            .unwrap_or(results.hits.len()),
+        hits: results
+            .hits
+            .into_iter()
+            .map(|hit| {
+                let formatted = |attribute: &str| {
+                    hit.formatted_result
+                        .as_ref()
+                        .and_then(|formatted| formatted.get(attribute))
+                        .and_then(|value| value.as_str())
+                        .map(|value| value.to_string())
+                };
+                let highlighted_body = match (&query.highlight, &hit.result.normalized_body) {
+                    // Matches in the normalized body aren't highlighted by Meilisearch.
+                    (Some((pre_tag, post_tag)), Some(_)) => {
+                        let ranges: Vec<_> = hit
+                            .matches_position
+                            .as_ref()
+                            .and_then(|positions| positions.get("normalized_body"))
+                            .map(|positions| {
+                                positions
+                                    .iter()
+                                    .map(|m| m.start..m.start + m.length)
+                                    .collect()
+                            })
+                            .unwrap_or_default();
+                        Some(highlight_body(&hit.result, &ranges, pre_tag, post_tag))
+                    }
+                    _ => formatted("body"),
+                };
+                SearchHit {
+                    highlighted_body,
+                    highlighted_ocr_body: formatted("ocr_body"),
+                    score: hit.ranking_score,
+                    message: hit.result,
+                }
+            })
+            .collect(),
+    }
+}
+
+#[async_trait]
+impl SearchBackend for MeilisearchBackend {
+    async fn create_index(&self, index: &str) -> Result<()> {
+        let client = &self.client;
+        let index = match client.get_index(index).await {
+            Ok(index) => index,
+            Err(Error::Meilisearch(e)) if e.error_code == ErrorCode::IndexNotFound => client
+                .create_index(index, Some("event_id"))
+                .await?
+                .wait_for_completion(client, None, Some(TASK_TIMEOUT))
+                .await?
+                .try_make_index(client)
+                .map_err(|task| anyhow!("Creating index failed: {:?}", task))?,
+            Err(e) => return Err(e.into()),
+        };
+        // Also update existing indices, for settings declared or relied on by newer versions.
+        let (settings, changes) = self.settings_changes(&index.uid, &index.get_settings().await?);
+        if changes.is_empty() {
+            return Ok(());
+        }
+        tracing::info!("Updating {} of index {}", changes.join(", "), index.uid);
+        let task = index.set_settings(&settings).await?;
+        self.wait(task, &format!("Updating settings of index {}", index.uid))
+            .await
+    }
+
+    async fn add_or_update(&self, index: &str, messages: &[LuoxuMessage]) -> Result<()> {
+        let task = self
+            .client
+            .index(index)
+            .add_or_update(messages, None::<&str>)
+            .await?;
+        self.wait(task, "Adding messages").await
+    }
+
+    async fn get(&self, index: &str, event_id: &KeyEventId) -> Result<Option<LuoxuMessage>> {
+        match self
+            .client
+            .index(index)
+            .get_document(event_id.as_str())
+            .await
+        {
+            Ok(message) => Ok(Some(message)),
+            Err(Error::Meilisearch(e)) if e.error_code == ErrorCode::DocumentNotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, index: &str, event_ids: &[KeyEventId]) -> Result<()> {
+        let ids: Vec<_> = event_ids.iter().map(|id| id.as_str()).collect();
+        let task = self.client.index(index).delete_documents(&ids).await?;
+        self.wait(task, "Deleting messages").await
+    }
+
+    async fn search(&self, index: &str, query: &SearchQuery) -> Result<SearchResults> {
+        let index = self.client.index(index);
+        let filter = filter_expression(&query.filter);
+        let results = Self::build_query(&index, query, &filter)
+            .execute::<LuoxuMessage>()
+            .await?;
+        Ok(convert_results(results, query))
+    }
+
+    async fn multi_search(&self, queries: &[(String, SearchQuery)]) -> Result<Vec<SearchResults>> {
+        let filters: Vec<_> = queries
+            .iter()
+            .map(|(_, query)| filter_expression(&query.filter))
+            .collect();
+        let indices: Vec<_> = queries
+            .iter()
+            .map(|(uid, _)| self.client.index(uid))
+            .collect();
+        let mut multi_search = MultiSearchQuery::new(&self.client);
+        for ((index, (_, query)), filter) in indices.iter().zip(queries).zip(&filters) {
+            multi_search.with_search_query(Self::build_query(index, query, filter));
+        }
+        let response = multi_search.execute::<LuoxuMessage>().await?;
+        Ok(response
+            .results
+            .into_iter()
+            .zip(queries)
+            .map(|(results, (_, query))| convert_results(results, query))
+            .collect())
+    }
+
+    async fn list_indices(&self) -> Result<Vec<String>> {
+        let mut result = Vec::new();
+        loop {
+            let page = self
+                .client
+                .list_all_indexes_with(
+                    IndexesQuery::new(&self.client)
+                        .with_offset(result.len())
+                        .with_limit(PAGE_SIZE),
+                )
+                .await?;
+            let count = page.results.len();
+            result.extend(page.results.into_iter().map(|index| index.uid));
+            if count < PAGE_SIZE {
+                return Ok(result);
+            }
+        }
+    }
+
+    async fn delete_index(&self, index: &str) -> Result<()> {
+        let task = self.client.delete_index(index).await?;
+        self.wait(task, "Deleting the index").await
+    }
+
+    async fn documents(&self, index: &str, rooms: &[OwnedRoomId]) -> Result<Vec<LuoxuMessage>> {
+        let index = self.client.index(index);
+        let filter = filter_expression(&SearchFilter {
+            rooms: rooms.to_vec(),
+            ..Default::default()
+        });
+        let mut result: Vec<LuoxuMessage> = Vec::new();
+        loop {
+            let mut query = DocumentsQuery::new(&index);
+            query.with_offset(result.len()).with_limit(PAGE_SIZE);
+            if !filter.is_empty() {
+                query.with_filter(&filter);
+            }
+            let page = index.get_documents_with::<LuoxuMessage>(&query).await?;
+            let count = page.results.len();
+            result.extend(page.results);
+            if count < PAGE_SIZE {
+                break;
+            }
+        }
+        result.sort_by_key(|msg| msg.timestamp);
+        Ok(result)
+    }
+
+    async fn settings(&self, index: &str) -> Result<serde_json::Value> {
+        let settings = self.client.index(index).get_settings().await?;
+        Ok(serde_json::to_value(settings)?)
+    }
+}
+
+/// Whether a list of live settings has the same items as the declared list.
+fn same_set(live: Option<&[String]>, declared: &[String]) -> bool {
+    let mut live = live.unwrap_or_default().to_vec();
+    let mut declared = declared.to_vec();
+    live.sort();
+    live.dedup();
+    declared.sort();
+    declared.dedup();
+    live == declared
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::OwnedUserId;
+
+    #[test]
+    fn filter_values_are_escaped() {
+        let filter = SearchFilter {
+            sender: Some(OwnedUserId::try_from(r#"@a"b\c:example.org"#).unwrap()),
+            rooms: vec![OwnedRoomId::try_from("!room:example.org").unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(
+            filter_expression(&filter),
+            r#"user_id = "@a\"b\\c:example.org" AND room_id IN ["!room:example.org"]"#
+        );
+    }
+}
@@ -0,0 +1,378 @@
+//! Search backends storing and querying indexed messages.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedUserId};
+use serde::Deserialize;
+use std::iter::Peekable;
+use std::ops::Range;
+use std::str::CharIndices;
+
+use crate::{ContentKind, KeyEventId, LuoxuMessage};
+
+pub mod meilisearch;
+pub mod query;
+pub mod shared;
+pub mod sqlite;
+pub mod tantivy;
+
+pub use self::meilisearch::{IndexSettings, MeilisearchBackend};
+pub use self::query::{ParsedQuery, QueryError};
+pub use self::shared::SharedIndexBackend;
+pub use self::sqlite::SqliteBackend;
+pub use self::tantivy::TantivyBackend;
+
+/// Number of hits returned when a query doesn't specify a limit.
+pub const DEFAULT_LIMIT: usize = 20;
+
+/// A place to store and search messages, split into named indices.
+#[async_trait]
+pub trait SearchBackend: Send + Sync {
+    /// Create an index if it doesn't exist yet.
+    async fn create_index(&self, index: &str) -> Result<()>;
+
+    /// Add messages to an index, replacing messages with the same event ID.
+    async fn add_or_update(&self, index: &str, messages: &[LuoxuMessage]) -> Result<()>;
+
+    /// Get a single message by its event ID.
+    async fn get(&self, index: &str, event_id: &KeyEventId) -> Result<Option<LuoxuMessage>>;
+
+    /// Delete messages by their event IDs.
+    async fn delete(&self, index: &str, event_ids: &[KeyEventId]) -> Result<()>;
+
+    /// Search an index.
+    async fn search(&self, index: &str, query: &SearchQuery) -> Result<SearchResults>;
+
+    /// List the names of all indices.
+    async fn list_indices(&self) -> Result<Vec<String>>;
+
+    /// Delete an index and all of its messages.
+    async fn delete_index(&self, index: &str) -> Result<()>;
+
+    /// Get the messages of an index sent in some rooms, or every message if empty, oldest first.
+    async fn documents(&self, index: &str, rooms: &[OwnedRoomId]) -> Result<Vec<LuoxuMessage>>;
+
+    /// Describe the settings of an index, for display.
+    async fn settings(&self, index: &str) -> Result<serde_json::Value>;
+
+    /// Run a query against each of several indices, returning results in the same order.
+    async fn multi_search(&self, queries: &[(String, SearchQuery)]) -> Result<Vec<SearchResults>> {
+        let mut results = Vec::with_capacity(queries.len());
+        for (index, query) in queries {
+            results.push(self.search(index, query).await?);
+        }
+        Ok(results)
+    }
+}
+
+/// How search results are ordered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchSort {
+    /// Newest messages first.
+    #[default]
+    Newest,
+    /// Oldest messages first.
+    Oldest,
+    /// Most relevant messages first.
+    Relevance,
+}
+
+/// Restrictions on the messages matched by a query.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchFilter {
+    /// Only match messages sent before this time.
+    pub before: Option<MilliSecondsSinceUnixEpoch>,
+    /// Only match messages sent after this time.
+    pub after: Option<MilliSecondsSinceUnixEpoch>,
+    /// Only match messages from this user.
+    pub sender: Option<OwnedUserId>,
+    /// Only match messages having all of these kinds of content.
+    pub has: Vec<ContentKind>,
+    /// Only match messages sent in one of these rooms, or in any room if empty.
+    pub rooms: Vec<OwnedRoomId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchQuery {
+    pub query: String,
+    pub filter: SearchFilter,
+    pub sort: SearchSort,
+    pub offset: usize,
+    pub limit: usize,
+    /// Tags to put around matched words in the body.
+    pub highlight: Option<(String, String)>,
+}
+
+impl SearchQuery {
+    pub fn new(query: impl Into<String>) -> Self {
+        SearchQuery {
+            query: query.into(),
+            filter: SearchFilter::default(),
+            sort: SearchSort::default(),
+            offset: 0,
+            limit: DEFAULT_LIMIT,
+            highlight: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub message: LuoxuMessage,
+    /// The body with matches highlighted, if highlighting was requested.
+    pub highlighted_body: Option<String>,
+    /// The OCR text of an image with matches highlighted, if highlighting was requested.
+    pub highlighted_ocr_body: Option<String>,
+    /// Relevance of the hit, only comparable within the same backend.
+    pub score: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+    pub estimated_total_hits: usize,
+}
+
+/// Whether a character belongs to a script written without spaces between words.
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{2E80}'..='\u{9FFF}'
+        | '\u{AC00}'..='\u{D7AF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{FF66}'..='\u{FF9F}'
+        | '\u{20000}'..='\u{2FA1F}')
+}
+
+/// Split a text into the byte ranges of its words.
+///
+/// Every CJK character is a word of its own, other text is split at characters that
+/// aren't alphanumeric.
+pub(crate) fn words(text: &str) -> Words<'_> {
+    Words {
+        chars: text.char_indices().peekable(),
+    }
+}
+
+pub(crate) struct Words<'a> {
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl Iterator for Words<'_> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Range<usize>> {
+        while let Some((offset, c)) = self.chars.next() {
+            if is_cjk(c) {
+                return Some(offset..offset + c.len_utf8());
+            }
+            if c.is_alphanumeric() {
+                let mut end = offset + c.len_utf8();
+                while let Some(&(next_offset, next)) = self.chars.peek() {
+                    if !next.is_alphanumeric() || is_cjk(next) {
+                        break;
+                    }
+                    end = next_offset + next.len_utf8();
+                    self.chars.next();
+                }
+                return Some(offset..end);
+            }
+        }
+        None
+    }
+}
+
+/// Surround the given byte ranges of a text with tags.
+///
+/// Adjacent ranges, such as single CJK characters of a word, share the same tags. Ranges
+/// are widened to whole characters and cut at the end of the text.
+pub(crate) fn highlight(
+    text: &str,
+    ranges: &[Range<usize>],
+    pre_tag: &str,
+    post_tag: &str,
+) -> String {
+    let mut ranges: Vec<_> = ranges
+        .iter()
+        .map(|range| floor_char_boundary(text, range.start)..ceil_char_boundary(text, range.end))
+        .filter(|range| range.start < range.end)
+        .collect();
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    let mut result = String::with_capacity(text.len());
+    let mut start = 0;
+    for range in &merged {
+        result.push_str(&text[start..range.start]);
+        result.push_str(pre_tag);
+        result.push_str(&text[range.clone()]);
+        result.push_str(post_tag);
+        start = range.end;
+    }
+    result.push_str(&text[start..]);
+    result
+}
+
+/// The start of the character at a byte offset of a text.
+fn floor_char_boundary(text: &str, mut offset: usize) -> usize {
+    offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+/// The end of the character at a byte offset of a text.
+fn ceil_char_boundary(text: &str, mut offset: usize) -> usize {
+    offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset += 1;
+    }
+    offset
+}
+
+/// Highlight a message given byte ranges matched in its search text,
+/// returning the highlighted body and OCR text.
+pub(crate) fn highlight_message(
+    msg: &LuoxuMessage,
+    ranges: &[Range<usize>],
+    pre_tag: &str,
+    post_tag: &str,
+) -> (String, Option<String>) {
+    let body_len = msg.search_body().len();
+    let body_ranges: Vec<_> = ranges
+        .iter()
+        .filter(|range| range.start < body_len)
+        .map(|range| range.start..range.end.min(body_len))
+        .collect();
+    let body = highlight_body(msg, &body_ranges, pre_tag, post_tag);
+    let ocr_body = msg.ocr_body.as_ref().map(|ocr_body| {
+        // The OCR text follows the body and a newline.
+        let offset = body_len + 1;
+        let ocr_ranges: Vec<_> = ranges
+            .iter()
+            .filter(|range| range.end > offset)
+            .map(|range| range.start.max(offset) - offset..range.end - offset)
+            .collect();
+        highlight(ocr_body, &ocr_ranges, pre_tag, post_tag)
+    });
+    (body, ocr_body)
+}
+
+/// Highlight the body of a message given byte ranges matched in its search body.
+///
+/// Ranges in a normalized body are moved to the same characters of the original body,
+/// and the body is left as is when the conversion changed the number of characters.
+pub(crate) fn highlight_body(
+    msg: &LuoxuMessage,
+    ranges: &[Range<usize>],
+    pre_tag: &str,
+    post_tag: &str,
+) -> String {
+    let normalized = match &msg.normalized_body {
+        Some(normalized) => normalized,
+        None => return highlight(&msg.body, ranges, pre_tag, post_tag),
+    };
+    let from: Vec<_> = normalized
+        .char_indices()
+        .map(|(offset, _)| offset)
+        .collect();
+    let to: Vec<_> = msg.body.char_indices().map(|(offset, _)| offset).collect();
+    if from.len() != to.len() {
+        return msg.body.clone();
+    }
+    let map = |offset: usize| match from.binary_search(&offset) {
+        Ok(i) => to[i],
+        Err(_) => msg.body.len(),
+    };
+    let ranges: Vec<_> = ranges
+        .iter()
+        .map(|range| map(range.start)..map(range.end))
+        .collect();
+    highlight(&msg.body, &ranges, pre_tag, post_tag)
+}
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::{OwnedEventId, UInt};
+
+    fn message(body: &str, normalized_body: Option<&str>, ocr_body: Option<&str>) -> LuoxuMessage {
+        LuoxuMessage {
+            body: body.to_string(),
+            event_id: OwnedEventId::try_from("$1").unwrap().into(),
+            external_url: None,
+            user_id: "@alice:example.org".try_into().unwrap(),
+            user_display_name: None,
+            user_avatar: None,
+            timestamp: MilliSecondsSinceUnixEpoch(UInt::from(1u32)),
+            room_id: "!room:example.org".try_into().unwrap(),
+            ocr_body: ocr_body.map(str::to_string),
+            normalized_body: normalized_body.map(str::to_string),
+            edited_at: None,
+            has: vec![],
+        }
+    }
+
+    #[test]
+    fn highlight_merges_adjacent_ranges() {
+        assert_eq!(
+            highlight("你好 world", &[7..12, 0..3, 3..6], "<b>", "</b>"),
+            "<b>你好</b> <b>world</b>"
+        );
+        assert_eq!(highlight("hello", &[], "<b>", "</b>"), "hello");
+    }
+
+    #[test]
+    fn highlight_widens_ranges_to_characters() {
+        // Inside the first and the second character.
+        assert_eq!(highlight("你好吗", &[1..4], "<b>", "</b>"), "<b>你好</b>吗");
+        // Past the end of the text.
+        assert_eq!(
+            highlight("你好", &[3..10, 20..30], "<b>", "</b>"),
+            "你<b>好</b>"
+        );
+        assert_eq!(
+            highlight("你好", &[Range { start: 4, end: 2 }], "<b>", "</b>"),
+            "你好"
+        );
+    }
+
+    #[test]
+    fn highlight_body_maps_normalized_offsets() {
+        // Full-width letters take three bytes, their normalized form one.
+        let msg = message("ＡＢ c", Some("ab c"), None);
+        assert_eq!(
+            highlight_body(&msg, &[0..2, 3..4], "<b>", "</b>"),
+            "<b>ＡＢ</b> <b>c</b>"
+        );
+        // Left as is when the conversion changed the number of characters.
+        let msg = message("ＡＢ c", Some("abc"), None);
+        assert_eq!(highlight_body(&msg, &[0..2], "<b>", "</b>"), "ＡＢ c");
+    }
+
+    #[test]
+    fn highlight_message_splits_ocr_ranges() {
+        let msg = message("hello", None, Some("world hello"));
+        assert_eq!(msg.search_text(), "hello\nworld hello");
+        let (body, ocr_body) = highlight_message(&msg, &[0..5, 6..11, 12..17], "<b>", "</b>");
+        assert_eq!(body, "<b>hello</b>");
+        assert_eq!(ocr_body.as_deref(), Some("<b>world</b> <b>hello</b>"));
+
+        // A range spanning the newline is split between both texts.
+        let (body, ocr_body) = highlight_message(&msg, &[3..8], "<b>", "</b>");
+        assert_eq!(body, "hel<b>lo</b>");
+        assert_eq!(ocr_body.as_deref(), Some("<b>wo</b>rld hello"));
+
+        let msg = message("你好", Some("你好"), None);
+        let (body, ocr_body) = highlight_message(&msg, &[3..6], "<b>", "</b>");
+        assert_eq!(body, "你<b>好</b>");
+        assert_eq!(ocr_body, None);
+    }
+}
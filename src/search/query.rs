@@ -0,0 +1,291 @@
+//! Parsing of user queries with `from:`, `before:`, `after:`, `has:` and `in:` operators.
+
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedUserId, UInt};
+use std::fmt;
+
+use super::{SearchFilter, SearchQuery};
+use crate::normalize::Normalizer;
+use crate::ContentKind;
+
+/// Operators recognized in queries, any other `name:value` term is searched as text.
+const OPERATORS: [&str; 5] = ["from", "before", "after", "has", "in"];
+const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+
+/// A malformed query, the message is meant to be shown to the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryError(String);
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// A query split into its text and operators.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParsedQuery {
+    /// The text to search, including quoted phrases.
+    pub text: String,
+    pub filter: SearchFilter,
+    /// Indices given with `in:`, empty when not restricted.
+    pub indices: Vec<String>,
+}
+
+impl ParsedQuery {
+    /// Parse a query.
+    ///
+    /// Dates are `YYYY-MM-DD` in UTC, `before:` excludes and `after:` follows the given day.
+    pub fn parse(input: &str) -> Result<Self, QueryError> {
+        let mut parsed = ParsedQuery::default();
+        let mut text = Vec::new();
+        for term in split_terms(input) {
+            let (operator, value) = match term.split_once(':') {
+                Some((operator, value)) if OPERATORS.contains(&operator) => (operator, value),
+                _ => {
+                    text.push(term);
+                    continue;
+                }
+            };
+            if value.is_empty() {
+                return Err(QueryError(format!("Missing value for {}:", operator)));
+            }
+            let filter = &mut parsed.filter;
+            match operator {
+                "from" => {
+                    let user_id = OwnedUserId::try_from(value).map_err(|_| {
+                        QueryError(format!(
+                            "Invalid user ID in from:{}, expected @user:example.org",
+                            value
+                        ))
+                    })?;
+                    if filter.sender.replace(user_id).is_some() {
+                        return Err(QueryError("from: can only be used once".to_string()));
+                    }
+                }
+                "before" => {
+                    let before = parse_date(operator, value, 0)?;
+                    filter.before = Some(filter.before.map_or(before, |b| b.min(before)));
+                }
+                "after" => {
+                    // Exclude the given day, up to its last millisecond.
+                    let after = parse_date(operator, value, DAY_MILLIS - 1)?;
+                    filter.after = Some(filter.after.map_or(after, |a| a.max(after)));
+                }
+                "has" => {
+                    let kind: ContentKind = value.parse().map_err(|_| {
+                        QueryError(format!(
+                            "Unknown has:{}, expected image, file, video or link",
+                            value
+                        ))
+                    })?;
+                    if !filter.has.contains(&kind) {
+                        filter.has.push(kind);
+                    }
+                }
+                "in" => parsed.indices.push(value.to_string()),
+                _ => unreachable!(),
+            }
+        }
+        parsed.text = text.join(" ");
+        Ok(parsed)
+    }
+
+    /// Build a search query for an index, converting the text to its Chinese variant.
+    pub fn search_query(&self, normalizer: &Normalizer, index: &str) -> SearchQuery {
+        let mut query = SearchQuery::new(normalizer.normalize_query(index, &self.text));
+        query.filter = self.filter.clone();
+        query
+    }
+
+    /// Whether an index may be searched, according to `in:`.
+    pub fn allows_index(&self, index: &str) -> bool {
+        self.indices.is_empty() || self.indices.iter().any(|i| i == index)
+    }
+}
+
+/// Split a query on whitespace, keeping quoted phrases and their quotes together.
+pub fn split_terms(input: &str) -> Vec<&str> {
+    let mut terms = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+    for (offset, c) in input.char_indices() {
+        if c.is_whitespace() && !quoted {
+            if let Some(start) = start.take() {
+                terms.push(&input[start..offset]);
+            }
+            continue;
+        }
+        if start.is_none() {
+            start = Some(offset);
+        }
+        if c == '"' {
+            quoted = !quoted;
+        }
+    }
+    if let Some(start) = start {
+        terms.push(&input[start..]);
+    }
+    terms
+}
+
+/// Parse a `YYYY-MM-DD` date into the UTC timestamp of its start, plus an offset.
+fn parse_date(
+    operator: &str,
+    value: &str,
+    offset: u64,
+) -> Result<MilliSecondsSinceUnixEpoch, QueryError> {
+    let error = || {
+        QueryError(format!(
+            "Invalid date in {}:{}, expected YYYY-MM-DD",
+            operator, value
+        ))
+    };
+    let parts: Vec<_> = value.split('-').collect();
+    let (year, month, day) = match parts[..] {
+        [year, month, day] if year.len() == 4 && month.len() == 2 && day.len() == 2 => (
+            year.parse::<i64>().map_err(|_| error())?,
+            month.parse::<u32>().map_err(|_| error())?,
+            day.parse::<u32>().map_err(|_| error())?,
+        ),
+        _ => return Err(error()),
+    };
+    if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+        return Err(error());
+    }
+    let days = u64::try_from(days_from_civil(year, month, day)).map_err(|_| error())?;
+    let millis = UInt::try_from(days * DAY_MILLIS + offset).map_err(|_| error())?;
+    Ok(MilliSecondsSinceUnixEpoch(millis))
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(days: u64, offset: u64) -> Option<MilliSecondsSinceUnixEpoch> {
+        Some(MilliSecondsSinceUnixEpoch(
+            UInt::try_from(days * DAY_MILLIS + offset).unwrap(),
+        ))
+    }
+
+    #[test]
+    fn split_terms_keeps_quoted_phrases() {
+        assert_eq!(
+            split_terms(r#"  hello "big  world" from:@a:b "unclosed phrase"#),
+            vec![
+                "hello",
+                r#""big  world""#,
+                "from:@a:b",
+                r#""unclosed phrase"#
+            ]
+        );
+        assert!(split_terms("   ").is_empty());
+    }
+
+    #[test]
+    fn parse_text_and_operators() {
+        let parsed =
+            ParsedQuery::parse(r#"cat "red fox" from:@alice:example.org has:image in:main"#)
+                .unwrap();
+        assert_eq!(parsed.text, r#"cat "red fox""#);
+        assert_eq!(
+            parsed.filter.sender.as_ref().map(|s| s.as_str()),
+            Some("@alice:example.org")
+        );
+        assert_eq!(parsed.filter.has, vec![ContentKind::Image]);
+        assert_eq!(parsed.indices, vec!["main"]);
+        assert!(parsed.allows_index("main"));
+        assert!(!parsed.allows_index("other"));
+    }
+
+    #[test]
+    fn unknown_operators_are_text() {
+        let parsed = ParsedQuery::parse("https://example.org to:me").unwrap();
+        assert_eq!(parsed.text, "https://example.org to:me");
+        assert_eq!(parsed.filter, SearchFilter::default());
+    }
+
+    #[test]
+    fn repeated_operators() {
+        let parsed = ParsedQuery::parse(
+            "before:2024-03-01 before:2024-02-01 after:2024-01-01 after:2024-01-10 \
+             has:link has:link has:file in:a in:b",
+        )
+        .unwrap();
+        assert_eq!(parsed.filter.before, date(19754, 0));
+        assert_eq!(parsed.filter.after, date(19732, DAY_MILLIS - 1));
+        assert_eq!(
+            parsed.filter.has,
+            vec![ContentKind::Link, ContentKind::File]
+        );
+        assert_eq!(parsed.indices, vec!["a", "b"]);
+        assert!(ParsedQuery::parse("from:@a:b from:@c:d").is_err());
+    }
+
+    #[test]
+    fn operator_errors() {
+        assert!(ParsedQuery::parse("from:").is_err());
+        assert!(ParsedQuery::parse("from:alice").is_err());
+        let error = ParsedQuery::parse("has:gif").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unknown has:gif, expected image, file, video or link"
+        );
+    }
+
+    #[test]
+    fn invalid_dates() {
+        for value in [
+            "2024-1-01",
+            "24-01-01",
+            "2024-00-10",
+            "2024-13-01",
+            "2024-04-31",
+            "2023-02-29",
+            "1900-02-29",
+            "2024-01-xx",
+            "2024/01/01",
+            "1969-12-31",
+        ] {
+            assert!(parse_date("before", value, 0).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn leap_years() {
+        assert_eq!(parse_date("before", "2024-02-29", 0).ok(), date(19782, 0));
+        assert_eq!(parse_date("before", "2000-02-29", 0).ok(), date(11016, 0));
+        assert_eq!(days_in_month(2100, 2), 28);
+        assert_eq!(days_in_month(2400, 2), 29);
+    }
+
+    #[test]
+    fn civil_days() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2024, 1, 1), 19723);
+    }
+}
@@ -0,0 +1,276 @@
+//! Keeps the messages of every group in a single index of another backend.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use matrix_sdk::ruma::OwnedRoomId;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use super::{SearchBackend, SearchQuery, SearchResults};
+use crate::{HeedStore, KeyEventId, LuoxuMessage};
+
+/// Search backend mapping the groups of the state store to rooms of one shared index.
+///
+/// Groups are the index names of the state store, and are searched by filtering the shared
+/// index to their rooms.
+pub struct SharedIndexBackend {
+    inner: Arc<dyn SearchBackend>,
+    index: String,
+    store: HeedStore,
+}
+
+impl SharedIndexBackend {
+    pub fn new(inner: Arc<dyn SearchBackend>, index: &str, store: HeedStore) -> Self {
+        SharedIndexBackend {
+            inner,
+            index: index.to_string(),
+            store,
+        }
+    }
+
+    /// Get the rooms of a group.
+    fn rooms(&self, group: &str) -> Result<Vec<OwnedRoomId>> {
+        let mut rooms = Vec::new();
+        for info in self.store.get_rooms()? {
+            if info.index_name == group {
+                rooms.push(OwnedRoomId::try_from(info.room_id)?);
+            }
+        }
+        Ok(rooms)
+    }
+
+    /// Restrict a query to the rooms of a group, returning `None` if it can't match anything.
+    fn group_query(&self, group: &str, query: &SearchQuery) -> Result<Option<SearchQuery>> {
+        let rooms = self.rooms(group)?;
+        let mut query = query.clone();
+        query.filter.rooms = match query.filter.rooms.is_empty() {
+            true => rooms,
+            false => query
+                .filter
+                .rooms
+                .into_iter()
+                .filter(|room_id| rooms.contains(room_id))
+                .collect(),
+        };
+        Ok((!query.filter.rooms.is_empty()).then_some(query))
+    }
+}
+
+fn empty_results() -> SearchResults {
+    SearchResults {
+        hits: vec![],
+        estimated_total_hits: 0,
+    }
+}
+
+#[async_trait]
+impl SearchBackend for SharedIndexBackend {
+    async fn create_index(&self, _group: &str) -> Result<()> {
+        self.inner.create_index(&self.index).await
+    }
+
+    async fn add_or_update(&self, _group: &str, messages: &[LuoxuMessage]) -> Result<()> {
+        self.inner.add_or_update(&self.index, messages).await
+    }
+
+    async fn get(&self, group: &str, event_id: &KeyEventId) -> Result<Option<LuoxuMessage>> {
+        let rooms = self.rooms(group)?;
+        Ok(self
+            .inner
+            .get(&self.index, event_id)
+            .await?
+            .filter(|msg| rooms.contains(&msg.room_id)))
+    }
+
+    async fn delete(&self, _group: &str, event_ids: &[KeyEventId]) -> Result<()> {
+        self.inner.delete(&self.index, event_ids).await
+    }
+
+    async fn search(&self, group: &str, query: &SearchQuery) -> Result<SearchResults> {
+        match self.group_query(group, query)? {
+            Some(query) => self.inner.search(&self.index, &query).await,
+            None => Ok(empty_results()),
+        }
+    }
+
+    async fn list_indices(&self) -> Result<Vec<String>> {
+        let groups: BTreeSet<_> = self
+            .store
+            .get_rooms()?
+            .into_iter()
+            .map(|info| info.index_name)
+            .collect();
+        Ok(groups.into_iter().collect())
+    }
+
+    async fn delete_index(&self, group: &str) -> Result<()> {
+        let event_ids: Vec<_> = self
+            .documents(group, &[])
+            .await?
+            .into_iter()
+            .map(|msg| msg.event_id)
+            .collect();
+        if !event_ids.is_empty() {
+            self.inner.delete(&self.index, &event_ids).await?;
+        }
+        Ok(())
+    }
+
+    async fn documents(&self, group: &str, rooms: &[OwnedRoomId]) -> Result<Vec<LuoxuMessage>> {
+        let mut group_rooms = self.rooms(group)?;
+        if !rooms.is_empty() {
+            group_rooms.retain(|room_id| rooms.contains(room_id));
+        }
+        if group_rooms.is_empty() {
+            return Ok(vec![]);
+        }
+        self.inner.documents(&self.index, &group_rooms).await
+    }
+
+    async fn settings(&self, _group: &str) -> Result<serde_json::Value> {
+        self.inner.settings(&self.index).await
+    }
+
+    async fn multi_search(&self, queries: &[(String, SearchQuery)]) -> Result<Vec<SearchResults>> {
+        let mut group_queries = Vec::with_capacity(queries.len());
+        for (group, query) in queries {
+            group_queries.push(self.group_query(group, query)?);
+        }
+        let shared: Vec<_> = group_queries
+            .iter()
+            .flatten()
+            .map(|query| (self.index.clone(), query.clone()))
+            .collect();
+        let mut results = self.inner.multi_search(&shared).await?.into_iter();
+        Ok(group_queries
+            .iter()
+            .map(|query| match query {
+                Some(_) => results.next().unwrap_or_else(empty_results),
+                None => empty_results(),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::SqliteBackend;
+    use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedEventId, UInt};
+
+    fn room(id: &str) -> OwnedRoomId {
+        OwnedRoomId::try_from(id).unwrap()
+    }
+
+    fn message(event_id: &str, room_id: &str, body: &str, timestamp: u32) -> LuoxuMessage {
+        LuoxuMessage {
+            body: body.to_string(),
+            event_id: OwnedEventId::try_from(event_id).unwrap().into(),
+            external_url: None,
+            user_id: "@alice:example.org".try_into().unwrap(),
+            user_display_name: None,
+            user_avatar: None,
+            timestamp: MilliSecondsSinceUnixEpoch(UInt::from(timestamp)),
+            room_id: room(room_id),
+            ocr_body: None,
+            normalized_body: None,
+            edited_at: None,
+            has: vec![],
+        }
+    }
+
+    /// A shared index with groups `a` of two rooms and `b` of one room.
+    async fn backend(dir: &tempfile::TempDir) -> SharedIndexBackend {
+        let store = HeedStore::new(dir.path().to_str().unwrap()).unwrap();
+        store.add_entry("!a1:example.org", "a", None).unwrap();
+        store.add_entry("!a2:example.org", "a", None).unwrap();
+        store.add_entry("!b:example.org", "b", None).unwrap();
+        let inner = SqliteBackend::new(dir.path().join("indices").to_str().unwrap()).unwrap();
+        let backend = SharedIndexBackend::new(Arc::new(inner), "shared", store);
+        backend.create_index("a").await.unwrap();
+        let messages = [
+            message("$1", "!a1:example.org", "hello one", 1),
+            message("$2", "!a2:example.org", "hello two", 2),
+            message("$3", "!b:example.org", "hello three", 3),
+        ];
+        backend.add_or_update("a", &messages).await.unwrap();
+        backend
+    }
+
+    fn event_ids(results: &SearchResults) -> Vec<String> {
+        results
+            .hits
+            .iter()
+            .map(|hit| hit.message.event_id.event_id())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn group_query_restricts_rooms() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = backend(&dir).await;
+        let query = SearchQuery::new("hello");
+        let grouped = backend.group_query("a", &query).unwrap().unwrap();
+        assert_eq!(
+            grouped.filter.rooms,
+            vec![room("!a1:example.org"), room("!a2:example.org")]
+        );
+
+        let mut query = SearchQuery::new("hello");
+        query.filter.rooms = vec![room("!a2:example.org"), room("!b:example.org")];
+        let grouped = backend.group_query("a", &query).unwrap().unwrap();
+        assert_eq!(grouped.filter.rooms, vec![room("!a2:example.org")]);
+
+        query.filter.rooms = vec![room("!b:example.org")];
+        assert!(backend.group_query("a", &query).unwrap().is_none());
+        assert!(backend
+            .group_query("missing", &SearchQuery::new("hello"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn search_and_documents_stay_in_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = backend(&dir).await;
+        let results = backend
+            .search("b", &SearchQuery::new("hello"))
+            .await
+            .unwrap();
+        assert_eq!(event_ids(&results), vec!["$3"]);
+        let documents = backend.documents("a", &[]).await.unwrap();
+        assert_eq!(documents.len(), 2);
+        let documents = backend
+            .documents("a", &[room("!b:example.org")])
+            .await
+            .unwrap();
+        assert!(documents.is_empty());
+        let event_id: KeyEventId = OwnedEventId::try_from("$3").unwrap().into();
+        assert!(backend.get("a", &event_id).await.unwrap().is_none());
+        assert_eq!(
+            backend.list_indices().await.unwrap(),
+            vec!["a".to_string(), "b".to_string()]
+        );
+
+        backend.delete_index("a").await.unwrap();
+        assert!(backend.documents("a", &[]).await.unwrap().is_empty());
+        assert_eq!(backend.documents("b", &[]).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn multi_search_keeps_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = backend(&dir).await;
+        let mut query = SearchQuery::new("hello");
+        query.sort = crate::search::SearchSort::Oldest;
+        let queries = [
+            ("missing".to_string(), query.clone()),
+            ("b".to_string(), query.clone()),
+            ("empty".to_string(), query.clone()),
+            ("a".to_string(), query),
+        ];
+        let results = backend.multi_search(&queries).await.unwrap();
+        let results: Vec<_> = results.iter().map(event_ids).collect();
+        assert_eq!(results, vec![vec![], vec!["$3"], vec![], vec!["$1", "$2"]]);
+    }
+}
@@ -0,0 +1,528 @@
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use matrix_sdk::ruma::OwnedRoomId;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::query::split_terms;
+use super::{
+    highlight_message, words, SearchBackend, SearchHit, SearchQuery, SearchResults, SearchSort,
+};
+use crate::{KeyEventId, LuoxuMessage};
+
+/// Name of the database file in the search directory.
+const DATABASE: &str = "messages.sqlite";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS indices (
+    name TEXT PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY,
+    index_name TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    UNIQUE (index_name, event_id)
+);
+CREATE INDEX IF NOT EXISTS messages_timestamp ON messages (index_name, timestamp);
+CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5 (
+    body,
+    tokenize = 'unicode61 remove_diacritics 0'
+);
+";
+
+/// Embedded search backend storing every index in a single SQLite database.
+///
+/// FTS5 doesn't split CJK text into words, so bodies are indexed with a space
+/// between every CJK character, and CJK words are searched as phrases.
+pub struct SqliteBackend {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteBackend {
+    pub fn new(path: &str) -> Result<Self> {
+        std::fs::create_dir_all(path)?;
+        let connection = Connection::open(Path::new(path).join(DATABASE))?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteBackend {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Run a closure on the connection in a blocking thread.
+    async fn with_connection<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || f(&mut connection.lock().unwrap())).await?
+    }
+}
+
+fn check_index(connection: &Connection, index: &str) -> Result<()> {
+    let exists = connection
+        .query_row(
+            "SELECT 1 FROM indices WHERE name = ?1",
+            params![index],
+            |_| Ok(()),
+        )
+        .optional()?;
+    match exists {
+        Some(()) => Ok(()),
+        None => bail!("Index {} not found", index),
+    }
+}
+
+fn delete_message(connection: &Connection, index: &str, event_id: &str) -> Result<()> {
+    let id: Option<i64> = connection
+        .query_row(
+            "SELECT id FROM messages WHERE index_name = ?1 AND event_id = ?2",
+            params![index, event_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(id) = id {
+        connection.execute("DELETE FROM messages_fts WHERE rowid = ?1", params![id])?;
+        connection.execute("DELETE FROM messages WHERE id = ?1", params![id])?;
+    }
+    Ok(())
+}
+
+fn search(connection: &Connection, index: &str, query: &SearchQuery) -> Result<SearchResults> {
+    check_index(connection, index)?;
+    let phrases: Vec<Vec<String>> = split_terms(&query.query)
+        .into_iter()
+        .map(|term| tokenize(term).into_iter().map(|(_, token)| token).collect())
+        .filter(|phrase: &Vec<String>| !phrase.is_empty())
+        .collect();
+
+    let mut conditions = vec!["m.index_name = ?".to_string()];
+    let mut values = vec![Value::Text(index.to_string())];
+    let from = if phrases.is_empty() {
+        "messages m"
+    } else {
+        let expression: Vec<_> = phrases
+            .iter()
+            .map(|phrase| format!("\"{}\"", phrase.join(" ")))
+            .collect();
+        conditions.push("messages_fts MATCH ?".to_string());
+        values.push(Value::Text(expression.join(" ")));
+        "messages_fts JOIN messages m ON m.id = messages_fts.rowid"
+    };
+    if let Some(before) = query.filter.before {
+        conditions.push("m.timestamp < ?".to_string());
+        values.push(Value::Integer(before.0.into()));
+    }
+    if let Some(after) = query.filter.after {
+        conditions.push("m.timestamp > ?".to_string());
+        values.push(Value::Integer(after.0.into()));
+    }
+    if let Some(sender) = &query.filter.sender {
+        conditions.push("m.user_id = ?".to_string());
+        values.push(Value::Text(sender.to_string()));
+    }
+    for kind in &query.filter.has {
+        conditions.push(
+            "EXISTS (SELECT 1 FROM json_each(m.message, '$.has') WHERE value = ?)".to_string(),
+        );
+        values.push(Value::Text(kind.as_str().to_string()));
+    }
+    if !query.filter.rooms.is_empty() {
+        let placeholders = vec!["?"; query.filter.rooms.len()].join(", ");
+        conditions.push(format!(
+            "json_extract(m.message, '$.room_id') IN ({})",
+            placeholders
+        ));
+        values.extend(
+            query
+                .filter
+                .rooms
+                .iter()
+                .map(|room_id| Value::Text(room_id.to_string())),
+        );
+    }
+    let conditions = conditions.join(" AND ");
+
+    let count: i64 = connection.query_row(
+        &format!("SELECT COUNT(*) FROM {} WHERE {}", from, conditions),
+        params_from_iter(&values),
+        |row| row.get(0),
+    )?;
+
+    // bm25() is lower for better matches.
+    let (score, order) = match (query.sort, phrases.is_empty()) {
+        (SearchSort::Relevance, false) => ("-bm25(messages_fts)", "bm25(messages_fts)"),
+        (SearchSort::Oldest, false) => ("-bm25(messages_fts)", "m.timestamp ASC"),
+        (_, false) => ("-bm25(messages_fts)", "m.timestamp DESC"),
+        (SearchSort::Oldest, true) => ("NULL", "m.timestamp ASC"),
+        (_, true) => ("NULL", "m.timestamp DESC"),
+    };
+    let sql = format!(
+        "SELECT m.message, {} FROM {} WHERE {} ORDER BY {} LIMIT {} OFFSET {}",
+        score, from, conditions, order, query.limit, query.offset
+    );
+    let mut statement = connection.prepare(&sql)?;
+    let rows = statement.query_map(params_from_iter(&values), |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, Option<f64>>(1)?))
+    })?;
+    let mut hits = Vec::new();
+    for row in rows {
+        let (message, score) = row?;
+        let message: LuoxuMessage = serde_json::from_str(&message)?;
+        let (highlighted_body, highlighted_ocr_body) = match &query.highlight {
+            Some((pre_tag, post_tag)) => {
+                let ranges = matches(&message.search_text(), &phrases);
+                let (body, ocr_body) = highlight_message(&message, &ranges, pre_tag, post_tag);
+                (Some(body), ocr_body)
+            }
+            None => (None, None),
+        };
+        hits.push(SearchHit {
+            message,
+            highlighted_body,
+            highlighted_ocr_body,
+            score,
+        });
+    }
+    Ok(SearchResults {
+        hits,
+        estimated_total_hits: count as usize,
+    })
+}
+
+/// Split text into lowercase tokens with their byte ranges.
+fn tokenize(text: &str) -> Vec<(Range<usize>, String)> {
+    words(text)
+        .map(|range| (range.clone(), text[range].to_lowercase()))
+        .collect()
+}
+
+/// Text stored in the full-text index for a body.
+fn indexed_text(body: &str) -> String {
+    let tokens: Vec<_> = tokenize(body).into_iter().map(|(_, token)| token).collect();
+    tokens.join(" ")
+}
+
+/// Byte ranges of a text matching any of the phrases, sorted by position.
+fn matches(text: &str, phrases: &[Vec<String>]) -> Vec<Range<usize>> {
+    let tokens = tokenize(text);
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for phrase in phrases {
+        for window in tokens.windows(phrase.len()) {
+            if window.iter().zip(phrase).all(|((_, a), b)| a == b) {
+                ranges.push(window[0].0.start..window[window.len() - 1].0.end);
+            }
+        }
+    }
+    ranges.sort_by_key(|range| range.start);
+    ranges
+}
+
+#[async_trait]
+impl SearchBackend for SqliteBackend {
+    async fn create_index(&self, index: &str) -> Result<()> {
+        let index = index.to_string();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT OR IGNORE INTO indices (name) VALUES (?1)",
+                params![index],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn add_or_update(&self, index: &str, messages: &[LuoxuMessage]) -> Result<()> {
+        let index = index.to_string();
+        let messages = messages.to_vec();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT OR IGNORE INTO indices (name) VALUES (?1)",
+                params![index],
+            )?;
+            for msg in &messages {
+                delete_message(&transaction, &index, msg.event_id.as_str())?;
+                transaction.execute(
+                    "INSERT INTO messages (index_name, event_id, user_id, timestamp, message)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        index,
+                        msg.event_id.as_str(),
+                        msg.user_id.as_str(),
+                        i64::from(msg.timestamp.0),
+                        serde_json::to_string(msg)?,
+                    ],
+                )?;
+                transaction.execute(
+                    "INSERT INTO messages_fts (rowid, body) VALUES (?1, ?2)",
+                    params![
+                        transaction.last_insert_rowid(),
+                        indexed_text(&msg.search_text())
+                    ],
+                )?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get(&self, index: &str, event_id: &KeyEventId) -> Result<Option<LuoxuMessage>> {
+        let index = index.to_string();
+        let event_id = event_id.clone();
+        self.with_connection(move |connection| {
+            check_index(connection, &index)?;
+            let message: Option<String> = connection
+                .query_row(
+                    "SELECT message FROM messages WHERE index_name = ?1 AND event_id = ?2",
+                    params![index, event_id.as_str()],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            match message {
+                Some(message) => Ok(Some(serde_json::from_str(&message)?)),
+                None => Ok(None),
+            }
+        })
+        .await
+    }
+
+    async fn delete(&self, index: &str, event_ids: &[KeyEventId]) -> Result<()> {
+        let index = index.to_string();
+        let event_ids = event_ids.to_vec();
+        self.with_connection(move |connection| {
+            check_index(connection, &index)?;
+            let transaction = connection.transaction()?;
+            for event_id in &event_ids {
+                delete_message(&transaction, &index, event_id.as_str())?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn search(&self, index: &str, query: &SearchQuery) -> Result<SearchResults> {
+        let index = index.to_string();
+        let query = query.clone();
+        self.with_connection(move |connection| search(connection, &index, &query))
+            .await
+    }
+
+    async fn list_indices(&self) -> Result<Vec<String>> {
+        self.with_connection(|connection| {
+            let mut statement = connection.prepare("SELECT name FROM indices ORDER BY name")?;
+            let names = statement.query_map([], |row| row.get(0))?;
+            Ok(names.collect::<rusqlite::Result<_>>()?)
+        })
+        .await
+    }
+
+    async fn delete_index(&self, index: &str) -> Result<()> {
+        let index = index.to_string();
+        self.with_connection(move |connection| {
+            check_index(connection, &index)?;
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "DELETE FROM messages_fts WHERE rowid IN
+                 (SELECT id FROM messages WHERE index_name = ?1)",
+                params![index],
+            )?;
+            transaction.execute("DELETE FROM messages WHERE index_name = ?1", params![index])?;
+            transaction.execute("DELETE FROM indices WHERE name = ?1", params![index])?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn documents(&self, index: &str, rooms: &[OwnedRoomId]) -> Result<Vec<LuoxuMessage>> {
+        let index = index.to_string();
+        let rooms = rooms.to_vec();
+        self.with_connection(move |connection| {
+            check_index(connection, &index)?;
+            let mut sql = "SELECT message FROM messages WHERE index_name = ?".to_string();
+            let mut values = vec![Value::Text(index)];
+            if !rooms.is_empty() {
+                let placeholders = vec!["?"; rooms.len()].join(", ");
+                sql.push_str(&format!(
+                    " AND json_extract(message, '$.room_id') IN ({})",
+                    placeholders
+                ));
+                values.extend(rooms.iter().map(|room_id| Value::Text(room_id.to_string())));
+            }
+            sql.push_str(" ORDER BY timestamp");
+            let mut statement = connection.prepare(&sql)?;
+            let rows =
+                statement.query_map(params_from_iter(&values), |row| row.get::<_, String>(0))?;
+            let mut result = Vec::new();
+            for message in rows {
+                result.push(serde_json::from_str(&message?)?);
+            }
+            Ok(result)
+        })
+        .await
+    }
+
+    async fn settings(&self, index: &str) -> Result<serde_json::Value> {
+        let index = index.to_string();
+        self.with_connection(move |connection| {
+            check_index(connection, &index)?;
+            let documents: i64 = connection.query_row(
+                "SELECT COUNT(*) FROM messages WHERE index_name = ?1",
+                params![index],
+                |row| row.get(0),
+            )?;
+            let tokenizer: Option<String> = connection
+                .query_row(
+                    "SELECT sql FROM sqlite_master WHERE name = 'messages_fts'",
+                    [],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(serde_json::json!({
+                "fts": tokenizer,
+                "documents": documents,
+            }))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ContentKind;
+    use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedEventId, UInt};
+
+    fn message(event_id: &str, room_id: &str, body: &str, has: Vec<ContentKind>) -> LuoxuMessage {
+        LuoxuMessage {
+            body: body.to_string(),
+            event_id: OwnedEventId::try_from(event_id).unwrap().into(),
+            external_url: None,
+            user_id: "@alice:example.org".try_into().unwrap(),
+            user_display_name: None,
+            user_avatar: None,
+            timestamp: MilliSecondsSinceUnixEpoch(UInt::from(1u32)),
+            room_id: room_id.try_into().unwrap(),
+            ocr_body: None,
+            normalized_body: None,
+            edited_at: None,
+            has,
+        }
+    }
+
+    async fn backend(dir: &tempfile::TempDir) -> SqliteBackend {
+        let backend = SqliteBackend::new(dir.path().to_str().unwrap()).unwrap();
+        backend.create_index("index").await.unwrap();
+        let messages = [
+            message("$1", "!a:example.org", "The quick brown fox", vec![]),
+            message("$2", "!a:example.org", "brown quick dogs", vec![]),
+            message(
+                "$3",
+                "!b:example.org",
+                "一只棕色的狐狸",
+                vec![ContentKind::Image],
+            ),
+            message(
+                "$4",
+                "!b:example.org",
+                "狐色 quick",
+                vec![ContentKind::Link],
+            ),
+        ];
+        backend.add_or_update("index", &messages).await.unwrap();
+        backend
+    }
+
+    async fn search_ids(backend: &SqliteBackend, query: &SearchQuery) -> Vec<String> {
+        let mut ids: Vec<_> = backend
+            .search("index", query)
+            .await
+            .unwrap()
+            .hits
+            .iter()
+            .map(|hit| hit.message.event_id.event_id())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn phrases_match_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = backend(&dir).await;
+        let query = SearchQuery::new("quick brown");
+        assert_eq!(search_ids(&backend, &query).await, vec!["$1", "$2"]);
+        let query = SearchQuery::new("\"quick brown\"");
+        assert_eq!(search_ids(&backend, &query).await, vec!["$1"]);
+        // CJK words are phrases of their characters.
+        let query = SearchQuery::new("狐狸");
+        assert_eq!(search_ids(&backend, &query).await, vec!["$3"]);
+        let query = SearchQuery::new("QUICK");
+        assert_eq!(search_ids(&backend, &query).await, vec!["$1", "$2", "$4"]);
+    }
+
+    #[tokio::test]
+    async fn filters_restrict_hits() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = backend(&dir).await;
+        let mut query = SearchQuery::new("");
+        query.filter.has = vec![ContentKind::Image];
+        assert_eq!(search_ids(&backend, &query).await, vec!["$3"]);
+
+        let mut query = SearchQuery::new("quick");
+        query.filter.rooms = vec!["!b:example.org".try_into().unwrap()];
+        assert_eq!(search_ids(&backend, &query).await, vec!["$4"]);
+        query
+            .filter
+            .rooms
+            .push("!a:example.org".try_into().unwrap());
+        assert_eq!(search_ids(&backend, &query).await, vec!["$1", "$2", "$4"]);
+        query.filter.has = vec![ContentKind::Link];
+        assert_eq!(search_ids(&backend, &query).await, vec!["$4"]);
+    }
+
+    #[test]
+    fn matches_find_phrases() {
+        let phrases = vec![
+            vec!["quick".to_string(), "brown".to_string()],
+            vec!["狐".to_string()],
+        ];
+        let text = "Quick brown, brown quick 狐狸";
+        assert_eq!(matches(text, &phrases), vec![0..11, 25..28]);
+        assert_eq!(
+            highlight_message(
+                &message("$1", "!a:example.org", text, vec![]),
+                &matches(text, &phrases),
+                "<b>",
+                "</b>"
+            )
+            .0,
+            "<b>Quick brown</b>, brown quick <b>狐</b>狸"
+        );
+    }
+
+    #[tokio::test]
+    async fn search_highlights_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = backend(&dir).await;
+        let mut query = SearchQuery::new("狐 quick");
+        query.highlight = Some(("<b>".to_string(), "</b>".to_string()));
+        let results = backend.search("index", &query).await.unwrap();
+        let bodies: Vec<_> = results
+            .hits
+            .iter()
+            .filter_map(|hit| hit.highlighted_body.clone())
+            .collect();
+        assert_eq!(bodies, vec!["<b>狐</b>色 <b>quick</b>"]);
+    }
+}
@@ -0,0 +1,561 @@
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use matrix_sdk::ruma::OwnedRoomId;
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tantivy::collector::{Count, DocSetCollector, TopDocs};
+use tantivy::query::{AllQuery, BooleanQuery, Occur, Query, QueryParser, RangeQuery, TermQuery};
+use tantivy::schema::{
+    Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, FAST, INDEXED, STORED,
+    STRING,
+};
+use tantivy::tokenizer::{LowerCaser, TextAnalyzer, Token, TokenStream, Tokenizer};
+use tantivy::{
+    doc, DocAddress, Index, IndexReader, IndexWriter, Order, ReloadPolicy, Searcher,
+    SnippetGenerator, TantivyDocument, Term,
+};
+
+use super::{
+    highlight_message, words, SearchBackend, SearchHit, SearchQuery, SearchResults, SearchSort,
+    Words,
+};
+use crate::{KeyEventId, LuoxuMessage};
+
+/// Name of the tokenizer used for message bodies.
+const TOKENIZER: &str = "luoxu";
+/// Memory budget of an index writer.
+const WRITER_MEMORY: usize = 50_000_000;
+
+/// Embedded search backend storing every index in a directory.
+pub struct TantivyBackend {
+    path: PathBuf,
+    indices: Mutex<HashMap<String, Arc<TantivyIndex>>>,
+}
+
+struct TantivyIndex {
+    index: Index,
+    reader: IndexReader,
+    /// Created on first write, so read-only processes don't take the writer lock.
+    writer: Mutex<Option<IndexWriter>>,
+    fields: Fields,
+}
+
+#[derive(Clone, Copy)]
+struct Fields {
+    event_id: Field,
+    body: Field,
+    user_id: Field,
+    timestamp: Field,
+    has: Field,
+    /// Missing from indices created before rooms could be filtered.
+    room_id: Option<Field>,
+    /// The whole message as JSON.
+    message: Field,
+}
+
+impl Fields {
+    fn schema() -> Schema {
+        let mut builder = Schema::builder();
+        builder.add_text_field("event_id", STRING | STORED);
+        let body = TextOptions::default().set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer(TOKENIZER)
+                .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+        );
+        builder.add_text_field("body", body);
+        builder.add_text_field("user_id", STRING);
+        builder.add_i64_field("timestamp", INDEXED | FAST);
+        builder.add_text_field("has", STRING);
+        builder.add_text_field("message", STORED);
+        builder.add_text_field("room_id", STRING);
+        builder.build()
+    }
+
+    fn from_schema(schema: &Schema) -> Result<Self> {
+        Ok(Fields {
+            event_id: schema.get_field("event_id")?,
+            body: schema.get_field("body")?,
+            user_id: schema.get_field("user_id")?,
+            timestamp: schema.get_field("timestamp")?,
+            has: schema.get_field("has")?,
+            room_id: schema.get_field("room_id").ok(),
+            message: schema.get_field("message")?,
+        })
+    }
+}
+
+impl TantivyIndex {
+    fn open(index: Index) -> Result<Self> {
+        index.tokenizers().register(
+            TOKENIZER,
+            TextAnalyzer::builder(CjkTokenizer::default())
+                .filter(LowerCaser)
+                .build(),
+        );
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommitWithDelay)
+            .try_into()?;
+        let fields = Fields::from_schema(&index.schema())?;
+        Ok(TantivyIndex {
+            index,
+            reader,
+            writer: Mutex::new(None),
+            fields,
+        })
+    }
+
+    /// Run a write operation and commit it.
+    fn write(&self, f: impl FnOnce(&IndexWriter, &Fields) -> Result<()>) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.is_none() {
+            *writer = Some(self.index.writer(WRITER_MEMORY)?);
+        }
+        let writer = writer.as_mut().unwrap();
+        f(writer, &self.fields)?;
+        writer.commit()?;
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    fn message(&self, searcher: &Searcher, address: DocAddress) -> Result<LuoxuMessage> {
+        let doc: TantivyDocument = searcher.doc(address)?;
+        match doc.get_first(self.fields.message).and_then(|v| v.as_str()) {
+            Some(message) => Ok(serde_json::from_str(message)?),
+            None => bail!("Document without a stored message"),
+        }
+    }
+
+    fn search(&self, query: &SearchQuery) -> Result<SearchResults> {
+        let fields = &self.fields;
+        let searcher = self.reader.searcher();
+        let text_query: Box<dyn Query> = if query.query.trim().is_empty() {
+            Box::new(AllQuery)
+        } else {
+            let mut parser = QueryParser::for_index(&self.index, vec![fields.body]);
+            parser.set_conjunction_by_default();
+            parser.parse_query_lenient(&query.query).0
+        };
+        let mut clauses = vec![(Occur::Must, text_query.box_clone())];
+        if query.filter.before.is_some() || query.filter.after.is_some() {
+            let lower = match query.filter.after {
+                Some(after) => Bound::Excluded(i64::from(after.0)),
+                None => Bound::Unbounded,
+            };
+            let upper = match query.filter.before {
+                Some(before) => Bound::Excluded(i64::from(before.0)),
+                None => Bound::Unbounded,
+            };
+            clauses.push((
+                Occur::Must,
+                Box::new(RangeQuery::new_i64_bounds(
+                    "timestamp".to_string(),
+                    lower,
+                    upper,
+                )),
+            ));
+        }
+        if let Some(sender) = &query.filter.sender {
+            clauses.push((
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(fields.user_id, sender.as_str()),
+                    IndexRecordOption::Basic,
+                )),
+            ));
+        }
+        for kind in &query.filter.has {
+            clauses.push((
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(fields.has, kind.as_str()),
+                    IndexRecordOption::Basic,
+                )),
+            ));
+        }
+        if !query.filter.rooms.is_empty() {
+            let room_id = match fields.room_id {
+                Some(room_id) => room_id,
+                None => bail!("Rooms can't be filtered in an index created before room filters"),
+            };
+            clauses.push((Occur::Must, rooms_query(room_id, &query.filter.rooms)));
+        }
+        let boolean_query = BooleanQuery::new(clauses);
+
+        // TopDocs doesn't accept a zero limit.
+        let top_docs = TopDocs::with_limit(query.limit.max(1)).and_offset(query.offset);
+        let (addresses, count): (Vec<(Option<f64>, DocAddress)>, usize) = match query.sort {
+            SearchSort::Newest | SearchSort::Oldest => {
+                let order = match query.sort {
+                    SearchSort::Oldest => Order::Asc,
+                    _ => Order::Desc,
+                };
+                let (top, count) = searcher.search(
+                    &boolean_query,
+                    &(
+                        top_docs.order_by_fast_field::<i64>("timestamp", order),
+                        Count,
+                    ),
+                )?;
+                (top.into_iter().map(|(_, a)| (None, a)).collect(), count)
+            }
+            SearchSort::Relevance => {
+                let (top, count) = searcher.search(&boolean_query, &(top_docs, Count))?;
+                let top = top
+                    .into_iter()
+                    .map(|(score, a)| (Some(score as f64), a))
+                    .collect();
+                (top, count)
+            }
+        };
+
+        let snippets = match &query.highlight {
+            Some(_) => {
+                let mut generator = SnippetGenerator::create(&searcher, &*text_query, fields.body)?;
+                generator.set_max_num_chars(usize::MAX);
+                Some(generator)
+            }
+            None => None,
+        };
+        let mut hits = Vec::new();
+        for (score, address) in addresses.into_iter().take(query.limit) {
+            let message = self.message(&searcher, address)?;
+            let (highlighted_body, highlighted_ocr_body) = match (&snippets, &query.highlight) {
+                (Some(snippets), Some((pre_tag, post_tag))) => {
+                    let snippet = snippets.snippet(&message.search_text());
+                    let (body, ocr_body) =
+                        highlight_message(&message, snippet.highlighted(), pre_tag, post_tag);
+                    (Some(body), ocr_body)
+                }
+                _ => (None, None),
+            };
+            hits.push(SearchHit {
+                message,
+                highlighted_body,
+                highlighted_ocr_body,
+                score,
+            });
+        }
+        Ok(SearchResults {
+            hits,
+            estimated_total_hits: count,
+        })
+    }
+}
+
+impl TantivyBackend {
+    pub fn new(path: &str) -> Result<Self> {
+        std::fs::create_dir_all(path)?;
+        Ok(TantivyBackend {
+            path: PathBuf::from(path),
+            indices: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Open an index, creating it if requested.
+    fn index(&self, name: &str, create: bool) -> Result<Arc<TantivyIndex>> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            bail!("Invalid index name: {}", name);
+        }
+        let mut indices = self.indices.lock().unwrap();
+        if let Some(index) = indices.get(name) {
+            return Ok(index.clone());
+        }
+        let path = self.path.join(name);
+        let index = if path.exists() {
+            Index::open_in_dir(&path)?
+        } else if create {
+            std::fs::create_dir_all(&path)?;
+            Index::create_in_dir(&path, Fields::schema())?
+        } else {
+            bail!("Index {} not found", name);
+        };
+        let index = Arc::new(TantivyIndex::open(index)?);
+        indices.insert(name.to_string(), index.clone());
+        Ok(index)
+    }
+}
+
+#[async_trait]
+impl SearchBackend for TantivyBackend {
+    async fn create_index(&self, index: &str) -> Result<()> {
+        self.index(index, true)?;
+        Ok(())
+    }
+
+    async fn add_or_update(&self, index: &str, messages: &[LuoxuMessage]) -> Result<()> {
+        let index = self.index(index, true)?;
+        let messages = messages.to_vec();
+        tokio::task::spawn_blocking(move || {
+            index.write(|writer, fields| {
+                for msg in &messages {
+                    writer.delete_term(Term::from_field_text(
+                        fields.event_id,
+                        msg.event_id.as_str(),
+                    ));
+                    let mut document = doc!(
+                        fields.event_id => msg.event_id.as_str(),
+                        fields.body => msg.search_text(),
+                        fields.user_id => msg.user_id.as_str(),
+                        fields.timestamp => i64::from(msg.timestamp.0),
+                        fields.message => serde_json::to_string(msg)?,
+                    );
+                    for kind in &msg.has {
+                        document.add_text(fields.has, kind.as_str());
+                    }
+                    if let Some(room_id) = fields.room_id {
+                        document.add_text(room_id, msg.room_id.as_str());
+                    }
+                    writer.add_document(document)?;
+                }
+                Ok(())
+            })
+        })
+        .await?
+    }
+
+    async fn get(&self, index: &str, event_id: &KeyEventId) -> Result<Option<LuoxuMessage>> {
+        let index = self.index(index, false)?;
+        let searcher = index.reader.searcher();
+        let query = TermQuery::new(
+            Term::from_field_text(index.fields.event_id, event_id.as_str()),
+            IndexRecordOption::Basic,
+        );
+        match searcher.search(&query, &TopDocs::with_limit(1))?.first() {
+            Some((_, address)) => Ok(Some(index.message(&searcher, *address)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn delete(&self, index: &str, event_ids: &[KeyEventId]) -> Result<()> {
+        let index = self.index(index, false)?;
+        let event_ids = event_ids.to_vec();
+        tokio::task::spawn_blocking(move || {
+            index.write(|writer, fields| {
+                for event_id in &event_ids {
+                    writer.delete_term(Term::from_field_text(fields.event_id, event_id.as_str()));
+                }
+                Ok(())
+            })
+        })
+        .await?
+    }
+
+    async fn search(&self, index: &str, query: &SearchQuery) -> Result<SearchResults> {
+        let index = self.index(index, false)?;
+        let query = query.clone();
+        tokio::task::spawn_blocking(move || index.search(&query)).await?
+    }
+
+    async fn list_indices(&self) -> Result<Vec<String>> {
+        let mut result = Vec::new();
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type()?.is_dir() && !name.starts_with('.') {
+                result.push(name);
+            }
+        }
+        result.sort();
+        Ok(result)
+    }
+
+    async fn delete_index(&self, index: &str) -> Result<()> {
+        let path = self.path.join(index);
+        self.index(index, false)?;
+        // Drop the writer and its lock before removing the files.
+        self.indices.lock().unwrap().remove(index);
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    async fn documents(&self, index: &str, rooms: &[OwnedRoomId]) -> Result<Vec<LuoxuMessage>> {
+        let index = self.index(index, false)?;
+        let searcher = index.reader.searcher();
+        let query: Box<dyn Query> = match index.fields.room_id {
+            Some(room_id) if !rooms.is_empty() => rooms_query(room_id, rooms),
+            _ => Box::new(AllQuery),
+        };
+        let mut result = Vec::new();
+        for address in searcher.search(&query, &DocSetCollector)? {
+            let msg = index.message(&searcher, address)?;
+            // Older indices can only be filtered once the messages are loaded.
+            if rooms.is_empty() || rooms.contains(&msg.room_id) {
+                result.push(msg);
+            }
+        }
+        result.sort_by_key(|msg| msg.timestamp);
+        Ok(result)
+    }
+
+    async fn settings(&self, index: &str) -> Result<serde_json::Value> {
+        let index = self.index(index, false)?;
+        Ok(serde_json::json!({
+            "schema": index.index.schema(),
+            "tokenizer": TOKENIZER,
+            "documents": index.reader.searcher().num_docs(),
+        }))
+    }
+}
+
+/// Match the messages of any of some rooms.
+fn rooms_query(room_id: Field, rooms: &[OwnedRoomId]) -> Box<dyn Query> {
+    let rooms: Vec<(Occur, Box<dyn Query>)> = rooms
+        .iter()
+        .map(|room| -> (Occur, Box<dyn Query>) {
+            (
+                Occur::Should,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(room_id, room.as_str()),
+                    IndexRecordOption::Basic,
+                )),
+            )
+        })
+        .collect();
+    Box::new(BooleanQuery::new(rooms))
+}
+
+/// A tokenizer splitting CJK text into single characters and other text into words.
+///
+/// The query parser turns consecutive characters into a phrase query, so CJK
+/// words are still matched as a whole.
+#[derive(Clone, Default)]
+pub struct CjkTokenizer {
+    token: Token,
+}
+
+pub struct CjkTokenStream<'a> {
+    text: &'a str,
+    words: Words<'a>,
+    token: &'a mut Token,
+}
+
+impl Tokenizer for CjkTokenizer {
+    type TokenStream<'a> = CjkTokenStream<'a>;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> CjkTokenStream<'a> {
+        self.token.reset();
+        CjkTokenStream {
+            text,
+            words: words(text),
+            token: &mut self.token,
+        }
+    }
+}
+
+impl TokenStream for CjkTokenStream<'_> {
+    fn advance(&mut self) -> bool {
+        self.token.text.clear();
+        self.token.position = self.token.position.wrapping_add(1);
+        match self.words.next() {
+            Some(range) => {
+                self.token.text.push_str(&self.text[range.clone()]);
+                self.token.offset_from = range.start;
+                self.token.offset_to = range.end;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn token(&self) -> &Token {
+        self.token
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        self.token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedEventId, UInt};
+    use std::ops::Range;
+
+    fn tokens(text: &str) -> Vec<(String, Range<usize>)> {
+        let mut tokenizer = CjkTokenizer::default();
+        let mut stream = tokenizer.token_stream(text);
+        let mut tokens = Vec::new();
+        while let Some(token) = stream.next() {
+            tokens.push((token.text.clone(), token.offset_from..token.offset_to));
+        }
+        tokens
+    }
+
+    #[test]
+    fn tokenizer_splits_cjk_characters() {
+        assert_eq!(
+            tokens("Hello世界, foo-bar 123東京"),
+            vec![
+                ("Hello".to_string(), 0..5),
+                ("世".to_string(), 5..8),
+                ("界".to_string(), 8..11),
+                ("foo".to_string(), 13..16),
+                ("bar".to_string(), 17..20),
+                ("123".to_string(), 21..24),
+                ("東".to_string(), 24..27),
+                ("京".to_string(), 27..30),
+            ]
+        );
+        assert!(tokens(" ,.!").is_empty());
+    }
+
+    fn message(event_id: &str, body: &str, ocr_body: Option<&str>) -> LuoxuMessage {
+        LuoxuMessage {
+            body: body.to_string(),
+            event_id: OwnedEventId::try_from(event_id).unwrap().into(),
+            external_url: None,
+            user_id: "@alice:example.org".try_into().unwrap(),
+            user_display_name: None,
+            user_avatar: None,
+            timestamp: MilliSecondsSinceUnixEpoch(UInt::from(1u32)),
+            room_id: "!room:example.org".try_into().unwrap(),
+            ocr_body: ocr_body.map(str::to_string),
+            normalized_body: None,
+            edited_at: None,
+            has: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn search_matches_cjk_words_and_highlights() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = TantivyBackend::new(dir.path().to_str().unwrap()).unwrap();
+        let messages = [
+            message("$1", "今天去东京了", None),
+            message("$2", "京东 is a shop", None),
+            message("$3", "see image", Some("东京 Tower")),
+        ];
+        backend.add_or_update("index", &messages).await.unwrap();
+
+        let mut query = SearchQuery::new("东京");
+        query.highlight = Some(("<b>".to_string(), "</b>".to_string()));
+        let results = backend.search("index", &query).await.unwrap();
+        let mut hits: Vec<_> = results
+            .hits
+            .iter()
+            .map(|hit| {
+                (
+                    hit.message.event_id.event_id(),
+                    hit.highlighted_body.clone().unwrap(),
+                    hit.highlighted_ocr_body.clone(),
+                )
+            })
+            .collect();
+        hits.sort();
+        assert_eq!(
+            hits,
+            vec![
+                ("$1".to_string(), "今天去<b>东京</b>了".to_string(), None),
+                (
+                    "$3".to_string(),
+                    "see image".to_string(),
+                    Some("<b>东京</b> Tower".to_string())
+                ),
+            ]
+        );
+    }
+}
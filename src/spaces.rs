@@ -0,0 +1,132 @@
+use anyhow::Context;
+use luoxu_rs::{index_name_from_template, LuoxuBotContext, LuoxuConfigSpace};
+use matrix_sdk::event_handler::Ctx;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::api::client::space::get_hierarchy;
+use matrix_sdk::ruma::events::space::child::OriginalSyncSpaceChildEvent;
+use matrix_sdk::ruma::room::RoomType;
+use matrix_sdk::ruma::{OwnedRoomId, RoomAliasId, RoomId};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::invites::index_joined_room;
+
+/// The configured spaces, and the spaces found in them.
+#[derive(Clone)]
+pub struct SpaceSettings {
+    pub spaces: HashMap<String, LuoxuConfigSpace>,
+    /// The name of the configured space a space belongs to, by space ID.
+    space_ids: Arc<RwLock<HashMap<OwnedRoomId, String>>>,
+}
+
+impl SpaceSettings {
+    pub fn new(spaces: HashMap<String, LuoxuConfigSpace>) -> Self {
+        SpaceSettings {
+            spaces,
+            space_ids: Default::default(),
+        }
+    }
+}
+
+/// Join and index the rooms of every configured space.
+pub async fn sync_spaces(
+    client: matrix_sdk::Client,
+    ctx: Arc<LuoxuBotContext>,
+    settings: SpaceSettings,
+) -> anyhow::Result<()> {
+    for name in settings.spaces.keys() {
+        if let Err(e) = sync_space(&client, &ctx, &settings, name).await {
+            tracing::warn!("Indexing the rooms of space {} failed: {:#}", name, e);
+        }
+    }
+    Ok(())
+}
+
+/// Walk the hierarchy of a space, joining and indexing the rooms not indexed yet.
+async fn sync_space(
+    client: &matrix_sdk::Client,
+    ctx: &LuoxuBotContext,
+    settings: &SpaceSettings,
+    name: &str,
+) -> anyhow::Result<()> {
+    let space = &settings.spaces[name];
+    let space_id = if space.space.starts_with('#') {
+        let alias = <&RoomAliasId>::try_from(space.space.as_str())?;
+        client.resolve_room_alias(alias).await?.room_id
+    } else {
+        OwnedRoomId::try_from(space.space.as_str())?
+    };
+    let mut token = None;
+    loop {
+        let mut request = get_hierarchy::v1::Request::new(&space_id);
+        request.from = token.as_deref();
+        let response = client.send(request, None).await?;
+        for chunk in response.rooms {
+            let room_id = chunk.room_id;
+            if chunk.room_type == Some(RoomType::Space) {
+                // Join the spaces to see their children change.
+                join(client, &room_id).await?;
+                settings
+                    .space_ids
+                    .write()
+                    .unwrap()
+                    .insert(room_id, name.to_string());
+                continue;
+            }
+            if ctx.store.get_index(room_id.clone())?.is_some() {
+                continue;
+            }
+            if let Err(e) = join(client, &room_id).await {
+                tracing::warn!("{:#}", e);
+                continue;
+            }
+            let index = match space.shared {
+                true => name.to_string(),
+                false => index_name_from_template(&space.index_name, room_id.as_str()),
+            };
+            if let Err(e) = index_joined_room(client, ctx, &room_id, &index, None).await {
+                tracing::warn!("Indexing {} failed: {:#}", room_id, e);
+            }
+        }
+        match response.next_batch {
+            Some(next_batch) => token = Some(next_batch),
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+async fn join(client: &matrix_sdk::Client, room_id: &RoomId) -> anyhow::Result<()> {
+    if client.get_joined_room(room_id).is_none() {
+        client
+            .join_room_by_id(room_id)
+            .await
+            .with_context(|| format!("Joining {} failed", room_id))?;
+    }
+    Ok(())
+}
+
+/// Index the rooms added to a configured space.
+pub async fn on_space_child(
+    ev: OriginalSyncSpaceChildEvent,
+    room: Room,
+    client: matrix_sdk::Client,
+    ctx: Ctx<Arc<LuoxuBotContext>>,
+    settings: Ctx<SpaceSettings>,
+) {
+    // Removed children have no `via` servers, their rooms are kept indexed.
+    if ev.content.via.as_ref().is_none_or(Vec::is_empty) {
+        return;
+    }
+    let name = match settings.space_ids.read().unwrap().get(room.room_id()) {
+        Some(name) => name.clone(),
+        None => return,
+    };
+    tracing::info!("{} was added to space {}", ev.state_key, name);
+    // Joining waits for the sync loop, so it can't happen in the handler.
+    tokio::spawn(async move {
+        if let Err(e) = sync_space(&client, &ctx, &settings, &name).await {
+            tracing::warn!("Indexing the rooms of space {} failed: {:#}", name, e);
+        }
+    });
+}
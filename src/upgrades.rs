@@ -0,0 +1,74 @@
+use anyhow::Context;
+use luoxu_rs::LuoxuBotContext;
+use matrix_sdk::ruma::RoomId;
+use std::sync::Arc;
+
+/// Join the replacement of an upgraded room and index it like the old one.
+pub async fn follow_upgrade(
+    client: &matrix_sdk::Client,
+    ctx: &LuoxuBotContext,
+    old_room_id: &RoomId,
+    new_room_id: &RoomId,
+) -> anyhow::Result<()> {
+    if ctx.store.get_index(old_room_id.to_owned())?.is_none() {
+        return Ok(());
+    }
+    tracing::info!("{} was upgraded to {}", old_room_id, new_room_id);
+    if client.get_joined_room(new_room_id).is_none() {
+        client
+            .join_room_by_id(new_room_id)
+            .await
+            .context("Joining the new room failed")?;
+    }
+    ctx.store
+        .add_upgrade(old_room_id.as_str(), new_room_id.as_str())?;
+    Ok(())
+}
+
+/// Catch up on the upgrades of indexed rooms that happened while the bot was offline.
+pub async fn follow_upgrades(
+    client: matrix_sdk::Client,
+    ctx: Arc<LuoxuBotContext>,
+) -> anyhow::Result<()> {
+    // Indexed rooms replaced by a room the bot didn't join yet.
+    for info in ctx.store.get_rooms()? {
+        if info.successor.is_some() {
+            continue;
+        }
+        let room_id = <&RoomId>::try_from(info.room_id.as_str())?;
+        let tombstone = client.get_room(room_id).and_then(|room| room.tombstone());
+        if let Some(tombstone) = tombstone {
+            if let Err(e) =
+                follow_upgrade(&client, &ctx, room_id, &tombstone.replacement_room).await
+            {
+                tracing::warn!("Following the upgrade of {} failed: {}", room_id, e);
+            }
+        }
+    }
+    // Joined rooms replacing an indexed room, e.g. after being invited to them. Anyone can
+    // name a predecessor when creating a room, so the old room must point back to it.
+    for room in client.joined_rooms() {
+        let predecessor = match room
+            .create_content()
+            .and_then(|content| content.predecessor)
+        {
+            Some(predecessor) => predecessor.room_id,
+            None => continue,
+        };
+        let confirmed = client
+            .get_room(&predecessor)
+            .and_then(|old| old.tombstone())
+            .is_some_and(|tombstone| tombstone.replacement_room == room.room_id());
+        if !confirmed {
+            continue;
+        }
+        if ctx.store.get_successor(predecessor.as_str())?.is_none()
+            && ctx
+                .store
+                .add_upgrade(predecessor.as_str(), room.room_id().as_str())?
+        {
+            tracing::info!("{} was upgraded to {}", predecessor, room.room_id());
+        }
+    }
+    Ok(())
+}